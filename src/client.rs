@@ -1,6 +1,16 @@
-use actix::{Actor, System};
+use std::future;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+
+use actix::{Actor, Addr, System};
 use clap::Clap;
+use tokio::process::Command;
 use tokio::signal::ctrl_c;
+use tokio::sync::oneshot;
+use tokio::time;
 
 pub mod errors;
 pub mod messages {
@@ -9,9 +19,13 @@ pub mod messages {
 pub mod messenger;
 pub mod stdin;
 
-use errors::ErrorServer;
-use messenger::MessengerServer;
-use stdin::StdinReaderServer;
+use crate::keys::{self, KeyError, KeyPair};
+use errors::{ErrorServer, StdoutWriteError};
+use messenger::{
+    Acquire, BfReserve, Credentials, FlushNamespace, ListNames, MessengerServer, MultisetMembers,
+    SetCreate, SetExpire, SetQuota, SetScan, StreamCommit, StreamRead, TopKList,
+};
+use stdin::{Sink, StdinReaderServer};
 
 #[derive(Clap)]
 pub struct Opts {
@@ -19,27 +33,631 @@ pub struct Opts {
     host: String,
     #[clap(short, long, default_value = "60054")]
     port: u16,
+    /// The most requests to have waiting on the server at once.
+    #[clap(long, default_value = "64")]
+    window: usize,
+    /// Keeps sets and multisets apart from those of other namespaces; the default namespace if
+    /// not given.
+    #[clap(long, default_value = "")]
+    namespace: String,
+    /// Encrypts the connection with CURVE, trusting the server with this public key.
+    #[clap(long)]
+    server_key: Option<String>,
+    /// The CURVE keypair to connect with, written by `kv keygen`; a new one is made up for each
+    /// run if not given, which only works if the server lets in any client.
+    #[clap(long, requires = "server-key")]
+    curve_key: Option<PathBuf>,
+    /// Presents the token in this file to a server expecting one.
+    #[clap(long, conflicts_with = "server-key")]
+    token_file: Option<PathBuf>,
+    /// The user name to present along with the token.
+    #[clap(long, default_value = "kv")]
+    user: String,
+    #[clap(subcommand)]
+    mode: Mode,
+}
+
+#[derive(Clap)]
+enum Mode {
+    /// Inserts each chunk of stdin, echoing the ones that had not been seen before.
+    Dedupe(DedupeOpts),
+    /// Tallies each chunk of stdin, printing the most frequent ones as count<TAB>value.
+    Topk(TopKOpts),
+    /// Counts each chunk of stdin in a multiset, then prints every member as count<TAB>value.
+    Count(CountOpts),
+    /// Waits on a shared rate limiter, then runs the given command.
+    Ratelimit(RateLimitOpts),
+    /// Appends each chunk of stdin to a stream.
+    Append(AppendOpts),
+    /// Prints the entries of a stream, optionally waiting for more.
+    Tail(TailOpts),
+    /// Prints every member of a set, optionally only those matching a prefix or glob.
+    Scan(ScanOpts),
+    /// Prints the names of the sets and multisets in the namespace.
+    Names(NamesOpts),
+    /// Removes every set and multiset in the namespace.
+    Flush(FlushOpts),
+    /// Limits how many members the namespace can hold, then prints how many it holds now.
+    Quota(QuotaOpts),
+    /// Has a set or multiset removed after a while, failing if there is no such set or multiset.
+    Expire(ExpireOpts),
+}
+
+#[derive(Clap)]
+struct DedupeOpts {
+    #[clap(short, long)]
+    name: String,
+    #[clap(short, long = "separator", default_value = "\n")]
+    sep: String,
+    /// The kind of structure to insert into; "set" is exact, "bloom" bounds memory at the cost of
+    /// the occasional false positive.
+    #[clap(long, default_value = "set")]
+    structure: Structure,
+    /// How many distinct values a new Bloom filter should be sized for.
+    #[clap(long, default_value = "100000")]
+    capacity: u64,
+    /// The false positive rate a new Bloom filter should be sized for.
+    #[clap(long, default_value = "0.01")]
+    error_rate: f64,
+    /// Caps a new set at this many members, so it only remembers recently seen chunks.
+    #[clap(long)]
+    max_members: Option<u64>,
+    /// What a capped set does once full: "evict-oldest" or "evict-random" to forget a chunk, or
+    /// "reject" to stop.
+    #[clap(long, default_value = "evict-oldest")]
+    overflow: Overflow,
+}
+
+#[derive(Clap)]
+struct TopKOpts {
+    #[clap(short, long)]
+    name: String,
+    #[clap(short, long = "separator", default_value = "\n")]
+    sep: String,
+    /// How many of the most frequent chunks to keep track of.
+    #[clap(short = "k", default_value = "10")]
+    k: u32,
+    /// Also print the current list every this many seconds, for inputs that never end or go quiet.
+    #[clap(short, long)]
+    interval: Option<u64>,
+}
+
+#[derive(Clap)]
+struct CountOpts {
+    #[clap(short, long)]
+    name: String,
+    #[clap(short, long = "separator", default_value = "\n")]
+    sep: String,
+}
+
+#[derive(Clap)]
+struct RateLimitOpts {
+    #[clap(short, long)]
+    name: String,
+    /// How many tokens are refilled per period, e.g. 5000/h; periods are s, m, h or d.
+    #[clap(short, long)]
+    rate: Rate,
+    /// How many tokens can be taken at once after the limiter has been idle.
+    #[clap(short, long, default_value = "1")]
+    burst: u64,
+    /// How many tokens this call takes.
+    #[clap(short, long, default_value = "1")]
+    cost: u64,
+    /// Exit with status 75 instead of waiting when no tokens are available.
+    #[clap(long)]
+    no_wait: bool,
+    /// The command to run once allowed.
+    #[clap(last = true)]
+    command: Vec<String>,
+}
+
+#[derive(Clap)]
+struct AppendOpts {
+    #[clap(short, long)]
+    name: String,
+    #[clap(short, long = "separator", default_value = "\n")]
+    sep: String,
+}
+
+#[derive(Clap)]
+struct TailOpts {
     #[clap(short, long)]
     name: String,
+    /// Printed after every entry.
     #[clap(short, long = "separator", default_value = "\n")]
     sep: String,
+    /// Start after the entry with this ID; 0 starts from the beginning.
+    #[clap(short, long, default_value = "0")]
+    after: u64,
+    /// Start after the group's committed ID instead, and commit each entry once printed.
+    #[clap(short, long)]
+    group: Option<String>,
+    /// Keep waiting for new entries instead of exiting once caught up.
+    #[clap(short, long)]
+    follow: bool,
+}
+
+#[derive(Clap)]
+struct ScanOpts {
+    #[clap(short, long)]
+    name: String,
+    /// Printed after every member.
+    #[clap(short, long = "separator", default_value = "\n")]
+    sep: String,
+    /// Only print members starting with this.
+    #[clap(long)]
+    prefix: Option<String>,
+    /// Only print members matching this glob, e.g. "job-*-done".
+    #[clap(short, long = "match")]
+    pattern: Option<String>,
+    /// How many members to ask for at a time.
+    #[clap(short, long, default_value = "1000")]
+    count: u32,
+}
+
+#[derive(Clap)]
+struct NamesOpts {
+    /// Only print names matching this glob, e.g. "jobs-*".
+    #[clap(short, long = "match")]
+    pattern: Option<String>,
+}
+
+#[derive(Clap)]
+struct FlushOpts {
+    /// Confirms that everything in the namespace should go.
+    #[clap(long)]
+    yes: bool,
+}
+
+#[derive(Clap)]
+struct QuotaOpts {
+    /// The most members the namespace's sets and multisets can hold between them; 0 lifts the
+    /// limit.
+    max_members: u64,
+}
+
+#[derive(Clap)]
+struct ExpireOpts {
+    #[clap(short, long)]
+    name: String,
+    /// How many seconds to keep it for; 0 keeps it indefinitely again.
+    ttl: u64,
+}
+
+/// A number of tokens per period, kept as tokens per second.
+struct Rate(f64);
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '/');
+        let count = parts
+            .next()
+            .and_then(|count| count.parse::<f64>().ok())
+            .filter(|count| *count > 0.0)
+            .ok_or_else(|| format!("{:?} does not start with a positive number", s))?;
+        let seconds = match parts.next().unwrap_or("s") {
+            "s" | "sec" | "second" => 1.0,
+            "m" | "min" | "minute" => 60.0,
+            "h" | "hr" | "hour" => 3600.0,
+            "d" | "day" => 86400.0,
+            period => return Err(format!("unknown period {:?}; expected s, m, h or d", period)),
+        };
+        Ok(Rate(count / seconds))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Structure {
+    Set,
+    Bloom,
+}
+
+#[derive(Clone, Copy)]
+pub enum Overflow {
+    Reject,
+    EvictOldest,
+    EvictRandom,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Overflow::Reject),
+            "evict-oldest" => Ok(Overflow::EvictOldest),
+            "evict-random" => Ok(Overflow::EvictRandom),
+            _ => Err(format!(
+                "unknown overflow policy {:?}; expected \"reject\", \"evict-oldest\" or \"evict-random\"",
+                s
+            )),
+        }
+    }
+}
+
+impl FromStr for Structure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "set" => Ok(Structure::Set),
+            "bloom" => Ok(Structure::Bloom),
+            _ => Err(format!("unknown structure {:?}; expected \"set\" or \"bloom\"", s)),
+        }
+    }
+}
+
+fn credentials(opts: &Opts) -> Result<Credentials, KeyError> {
+    if let Some(path) = &opts.token_file {
+        return Ok(Credentials::Token {
+            user: opts.user.clone(),
+            token: keys::read_token(path)?,
+        });
+    }
+    let server_key = match &opts.server_key {
+        None => return Ok(Credentials::None),
+        Some(server_key) => keys::parse(server_key)?,
+    };
+    let keypair = match &opts.curve_key {
+        Some(path) => keys::read_keypair(path)?,
+        None => match KeyPair::generate() {
+            Ok(keypair) => keypair,
+            Err(error) => {
+                eprintln!("Could not generate a keypair: {}", error);
+                process::exit(1);
+            }
+        },
+    };
+    Ok(Credentials::Curve { keypair, server_key })
 }
 
 // TODO: UTF-8 delimiters
 
 pub async fn start(opts: &Opts) {
+    let credentials = match credentials(opts) {
+        Ok(credentials) => credentials,
+        Err(error) => {
+            eprintln!("{}", error);
+            // EX_CONFIG from sysexits.h.
+            process::exit(78);
+        }
+    };
+
+    let (done_tx, done_rx) = oneshot::channel();
+    let window = opts.window;
     let error_server = ErrorServer::new().start();
-    let messenger_server =
-        MessengerServer::new(&opts.host, opts.port, error_server.clone()).start();
-    let stdin_server = StdinReaderServer::new(
+    let messenger_server = MessengerServer::new(
+        &opts.host,
+        opts.port,
+        &opts.namespace,
+        opts.window,
+        credentials,
         error_server.clone(),
-        messenger_server,
-        opts.name.clone(),
-        opts.sep.clone().into_bytes(),
     )
     .start();
 
-    // TODO: Is panicing appropriate here?
-    ctrl_c().await.unwrap();
+    match &opts.mode {
+        Mode::Dedupe(opts) => {
+            // Size the filter, or cap the set, before the first add would create one with the
+            // server's defaults.  If it already exists, it is used as-is.
+            match (opts.structure, opts.max_members) {
+                (Structure::Bloom, _) => {
+                    let _ = messenger_server
+                        .send(BfReserve {
+                            name: opts.name.clone(),
+                            capacity: opts.capacity,
+                            error_rate: opts.error_rate,
+                        })
+                        .await;
+                }
+                (Structure::Set, Some(max_members)) => {
+                    let _ = messenger_server
+                        .send(SetCreate {
+                            name: opts.name.clone(),
+                            max_members,
+                            overflow: opts.overflow,
+                        })
+                        .await;
+                }
+                (Structure::Set, None) => (),
+            }
+
+            let sink = match opts.structure {
+                Structure::Set => Sink::Set,
+                Structure::Bloom => Sink::Bloom,
+            };
+            let _stdin_server = StdinReaderServer::new(
+                error_server.clone(),
+                messenger_server,
+                opts.name.clone(),
+                opts.sep.clone().into_bytes(),
+                sink,
+                window,
+                done_tx,
+            )
+            .start();
+
+            // Every chunk on stdin has been sent and answered once done_rx resolves.
+            tokio::select! {
+                // TODO: Is panicing appropriate here?
+                result = ctrl_c() => result.unwrap(),
+                _ = done_rx => (),
+            }
+        }
+        Mode::Topk(opts) => {
+            let _stdin_server = StdinReaderServer::new(
+                error_server.clone(),
+                messenger_server.clone(),
+                opts.name.clone(),
+                opts.sep.clone().into_bytes(),
+                Sink::TopK { k: opts.k },
+                window,
+                done_tx,
+            )
+            .start();
+
+            let report = async {
+                match opts.interval {
+                    Some(seconds) => {
+                        // Stdin is read on a thread of its own, so this keeps time while input is
+                        // idle, too.  Waiting out the period after each list, rather than ticking,
+                        // keeps a slow reply from bunching the lists that follow it together.
+                        loop {
+                            time::delay_for(Duration::from_secs(seconds.max(1))).await;
+                            print_top_k(&messenger_server, &error_server, &opts.name).await;
+                            println!();
+                        }
+                    }
+                    None => future::pending::<()>().await,
+                }
+            };
+
+            // Inputs like `tail -f` never end, so print what we have when interrupted, too.
+            tokio::select! {
+                // TODO: Is panicing appropriate here?
+                result = ctrl_c() => result.unwrap(),
+                _ = done_rx => (),
+                _ = report => (),
+            }
+            print_top_k(&messenger_server, &error_server, &opts.name).await;
+        }
+        Mode::Count(opts) => {
+            let _stdin_server = StdinReaderServer::new(
+                error_server.clone(),
+                messenger_server.clone(),
+                opts.name.clone(),
+                opts.sep.clone().into_bytes(),
+                Sink::Multiset,
+                window,
+                done_tx,
+            )
+            .start();
+
+            tokio::select! {
+                // TODO: Is panicing appropriate here?
+                result = ctrl_c() => result.unwrap(),
+                _ = done_rx => {
+                    let request = MultisetMembers { name: opts.name.clone() };
+                    if let Ok(Ok(mut members)) = messenger_server.send(request).await {
+                        // Sorted by value, like `sort | uniq -c`.
+                        members.sort();
+                        print_counts(&error_server, members);
+                    }
+                }
+            }
+        }
+        Mode::Ratelimit(opts) => {
+            let request = Acquire {
+                name: opts.name.clone(),
+                rate: opts.rate.0,
+                burst: opts.burst,
+                cost: opts.cost,
+                block: !opts.no_wait,
+            };
+            let status = match messenger_server.send(request).await {
+                Ok(Ok((true, _))) => match opts.command.split_first() {
+                    None => 0,
+                    Some((program, args)) => match Command::new(program).args(args).status().await {
+                        Ok(status) => status.code().unwrap_or(1),
+                        Err(error) => {
+                            eprintln!("Could not run {}: {}", program, error);
+                            127
+                        }
+                    },
+                },
+                Ok(Ok((false, retry_after))) => {
+                    eprintln!("Rate limited; retry after {:.3}s", retry_after.as_secs_f64());
+                    // EX_TEMPFAIL from sysexits.h.
+                    75
+                }
+                // Failures were already reported by the messenger.
+                _ => 1,
+            };
+            process::exit(status);
+        }
+        Mode::Append(opts) => {
+            let _stdin_server = StdinReaderServer::new(
+                error_server.clone(),
+                messenger_server,
+                opts.name.clone(),
+                opts.sep.clone().into_bytes(),
+                Sink::Stream,
+                window,
+                done_tx,
+            )
+            .start();
+
+            tokio::select! {
+                // TODO: Is panicing appropriate here?
+                result = ctrl_c() => result.unwrap(),
+                _ = done_rx => (),
+            }
+        }
+        Mode::Tail(opts) => {
+            tokio::select! {
+                // TODO: Is panicing appropriate here?
+                result = ctrl_c() => result.unwrap(),
+                _ = tail(&messenger_server, &error_server, opts) => (),
+            }
+        }
+        Mode::Scan(opts) => {
+            tokio::select! {
+                // TODO: Is panicing appropriate here?
+                result = ctrl_c() => result.unwrap(),
+                _ = scan(&messenger_server, &error_server, opts) => (),
+            }
+        }
+        Mode::Names(opts) => {
+            let request = ListNames {
+                pattern: opts.pattern.clone().unwrap_or_default().into_bytes(),
+            };
+            // Failures were already reported by the messenger.
+            if let Ok(Ok(names)) = messenger_server.send(request).await {
+                for name in names {
+                    println!("{}", name);
+                }
+            }
+        }
+        Mode::Flush(opts) => {
+            if !opts.yes {
+                eprintln!("Refusing to flush the namespace without --yes");
+                process::exit(2);
+            }
+            if let Ok(Ok(removed)) = messenger_server.send(FlushNamespace).await {
+                eprintln!("Removed {} sets and multisets", removed);
+            }
+        }
+        Mode::Quota(opts) => {
+            let request = SetQuota {
+                max_members: opts.max_members,
+            };
+            if let Ok(Ok(members)) = messenger_server.send(request).await {
+                println!("{}", members);
+            }
+        }
+        Mode::Expire(opts) => {
+            let request = SetExpire {
+                name: opts.name.clone(),
+                ttl_ms: opts.ttl.saturating_mul(1000),
+            };
+            if let Ok(Ok(false)) = messenger_server.send(request).await {
+                eprintln!("No set or multiset named {}", opts.name);
+                process::exit(1);
+            }
+        }
+    }
+
     System::current().stop();
 }
+
+// How long a single blocking read waits before asking again, when following a stream.
+const FOLLOW_BLOCK_MS: u64 = 5000;
+
+// Prints entries as they're read, committing them for the group (if any) once printed; a crash
+// after printing but before committing means they are printed again by the next reader.
+async fn tail(messenger_server: &Addr<MessengerServer>, error_server: &Addr<ErrorServer>, opts: &TailOpts) {
+    let mut after = opts.after;
+    loop {
+        let request = StreamRead {
+            name: opts.name.clone(),
+            group: opts.group.clone(),
+            after,
+            count: 0,
+            block_ms: if opts.follow { FOLLOW_BLOCK_MS } else { 0 },
+        };
+        let entries = match messenger_server.send(request).await {
+            Ok(Ok(entries)) => entries,
+            // Failures were already reported by the messenger.
+            _ => return,
+        };
+
+        match entries.last() {
+            None if opts.follow => continue,
+            None => return,
+            Some((id, _)) => after = *id,
+        }
+
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        for (_, value) in entries {
+            let written = stdout
+                .write_all(&value)
+                .and_then(|()| stdout.write_all(opts.sep.as_bytes()));
+            if let Err(error) = written {
+                error_server.do_send(StdoutWriteError(error));
+                return;
+            }
+        }
+
+        if let Some(group) = &opts.group {
+            let request = StreamCommit {
+                name: opts.name.clone(),
+                group: group.clone(),
+                id: after,
+            };
+            if let Err(_) | Ok(Err(_)) = messenger_server.send(request).await {
+                return;
+            }
+        }
+    }
+}
+
+// Prints members a page at a time, until the scan is complete.
+async fn scan(messenger_server: &Addr<MessengerServer>, error_server: &Addr<ErrorServer>, opts: &ScanOpts) {
+    let mut cursor = vec![];
+    loop {
+        let request = SetScan {
+            name: opts.name.clone(),
+            cursor,
+            count: opts.count,
+            prefix: opts.prefix.clone().unwrap_or_default().into_bytes(),
+            pattern: opts.pattern.clone().unwrap_or_default().into_bytes(),
+        };
+        let (members, next) = match messenger_server.send(request).await {
+            Ok(Ok(page)) => page,
+            // Failures were already reported by the messenger.
+            _ => return,
+        };
+
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        for member in members {
+            let written = stdout
+                .write_all(&member)
+                .and_then(|()| stdout.write_all(opts.sep.as_bytes()));
+            if let Err(error) = written {
+                error_server.do_send(StdoutWriteError(error));
+                return;
+            }
+        }
+
+        if next.is_empty() {
+            return;
+        }
+        cursor = next;
+    }
+}
+
+// Prints the top-K list as count<TAB>value lines, most frequent first.
+async fn print_top_k(messenger_server: &Addr<MessengerServer>, error_server: &Addr<ErrorServer>, name: &str) {
+    // Failures were already reported by the messenger.
+    if let Ok(Ok(members)) = messenger_server.send(TopKList { name: name.to_owned() }).await {
+        print_counts(error_server, members);
+    }
+}
+
+fn print_counts(error_server: &Addr<ErrorServer>, members: Vec<(Vec<u8>, u64)>) {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for (value, count) in members {
+        let written = write!(stdout, "{}\t", count)
+            .and_then(|()| stdout.write_all(&value))
+            .and_then(|()| stdout.write_all(b"\n"));
+        if let Err(error) = written {
+            error_server.do_send(StdoutWriteError(error));
+            return;
+        }
+    }
+}
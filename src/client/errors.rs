@@ -2,9 +2,12 @@ use std::io;
 
 use actix::{Actor, Context, Handler, Message};
 use log::error;
+use prost;
 use simple_logger::SimpleLogger;
 use zmq;
 
+use crate::client::messages as cm;
+
 pub struct ErrorServer {
     engine: SimpleLogger,
 }
@@ -98,3 +101,228 @@ impl Handler<StdinReadError> for ErrorServer {
         error!("Could not read from stdin; got error: {}", error)
     }
 }
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SocketRecvError {
+    pub error: zmq::Error,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Handler<SocketRecvError> for ErrorServer {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        SocketRecvError { error, host, port }: SocketRecvError,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        error!(
+            "Could not receive a message over the ZeroMQ socket at tcp://{}:{}; got error: {}",
+            host, port, error
+        )
+    }
+}
+
+// Something went wrong with the descriptor the client sleeps on until the socket has data.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SocketWaitError {
+    pub error: io::Error,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Handler<SocketWaitError> for ErrorServer {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        SocketWaitError { error, host, port }: SocketWaitError,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        error!(
+            "Could not wait for a message on the ZeroMQ socket at tcp://{}:{}; got error: {}",
+            host, port, error
+        )
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct MessageDecodeError(pub Option<prost::DecodeError>, pub Vec<u8>);
+
+impl Handler<MessageDecodeError> for ErrorServer {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        MessageDecodeError(error, message): MessageDecodeError,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        match error {
+            Some(error) => error!(
+                "Could not decode a response from the server; got this error with this message (base64-encoded): {}, {}",
+                error,
+                base64::encode(&message)
+            ),
+            None => error!(
+                "Got an unexpected response from the server (base64-encoded): {}",
+                base64::encode(&message)
+            ),
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct StdoutWriteError(pub io::Error);
+
+impl Handler<StdoutWriteError> for ErrorServer {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        StdoutWriteError(error): StdoutWriteError,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        error!("Could not write to stdout; got error: {}", error)
+    }
+}
+
+// The server answered a request with an error.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ServerError {
+    pub code: Option<cm::ErrorCode>,
+    pub message: String,
+    pub request_id: u64,
+}
+
+impl Handler<ServerError> for ErrorServer {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        ServerError { code, message, request_id }: ServerError,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        match code {
+            Some(code) => error!("Request {} failed with {:?}: {}", request_id, code, message),
+            None => error!("Request {} failed with an unknown error: {}", request_id, message),
+        }
+    }
+}
+
+// A response came back for a request that isn't waiting on one.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct UnmatchedResponseError {
+    pub sequence: u64,
+}
+
+impl Handler<UnmatchedResponseError> for ErrorServer {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        UnmatchedResponseError { sequence }: UnmatchedResponseError,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        error!("Got a response to request {}, which is not waiting on one", sequence)
+    }
+}
+
+// The server doesn't speak this client's protocol version.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct UnsupportedVersionError {
+    pub version: u32,
+    pub min_version: u32,
+    pub max_version: u32,
+}
+
+impl Handler<UnsupportedVersionError> for ErrorServer {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        UnsupportedVersionError { version, min_version, max_version }: UnsupportedVersionError,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        error!(
+            "The server only speaks protocol versions {} through {}, but this client speaks version {}; upgrade whichever is older",
+            min_version, max_version, version
+        )
+    }
+}
+
+// The server doesn't support some of what this client can ask for, most likely because it's older.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct MissingCapabilitiesError(pub Vec<String>);
+
+impl Handler<MissingCapabilitiesError> for ErrorServer {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        MissingCapabilitiesError(capabilities): MissingCapabilitiesError,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        error!(
+            "The server does not support these capabilities, so requests using them will fail: {}",
+            capabilities.join(", ")
+        )
+    }
+}
+
+// Security couldn't be set up on the socket, so nothing was sent.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SocketSecurityError(pub zmq::Error);
+
+impl Handler<SocketSecurityError> for ErrorServer {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        SocketSecurityError(error): SocketSecurityError,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        error!("Could not set up authentication on the ZeroMQ socket; got error: {}", error)
+    }
+}
+
+// The server wouldn't complete the security handshake, either because it turned down this
+// client's token or key, or because the two sides aren't set up for the same mechanism.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct HandshakeFailedError {
+    pub refused: bool,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Handler<HandshakeFailedError> for ErrorServer {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        HandshakeFailedError { refused, host, port }: HandshakeFailedError,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        if refused {
+            error!(
+                "The server at tcp://{}:{} turned this client away; check its token or CURVE key",
+                host, port
+            )
+        } else {
+            error!(
+                "Could not complete the security handshake with tcp://{}:{}; check that both sides use the same mechanism, and the right server key",
+                host, port
+            )
+        }
+    }
+}
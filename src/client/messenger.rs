@@ -1,42 +1,142 @@
-use actix::{Actor, Addr, Context, Handler};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Cursor};
+use std::pin::Pin;
+use std::task::{self, Poll};
+use std::time::Duration;
+
+use actix::{Actor, ActorContext, ActorFuture, Addr, AsyncContext, Context, Handler, ResponseFuture};
 use prost::Message;
+use tokio::sync::oneshot;
 use zmq;
 
-use crate::client::errors::{ErrorServer, SocketConnectionError, SocketOpenError, SocketSendError};
+use crate::client::errors::{
+    ErrorServer, HandshakeFailedError, MessageDecodeError, MissingCapabilitiesError, ServerError,
+    SocketConnectionError, SocketOpenError, SocketRecvError, SocketSecurityError, SocketSendError,
+    SocketWaitError, UnmatchedResponseError, UnsupportedVersionError,
+};
+use crate::client::messages as cm;
+use crate::client::Overflow;
+use crate::keys::KeyPair;
 use crate::server::messages as m;
+use crate::server::readiness::Readiness;
+
+// The protocol version this client speaks; see the server's messenger.
+const PROTOCOL_VERSION: u32 = 1;
+
+// Everything the client may ask the server to do.
+const CAPABILITIES: &[&str] = &[
+    "set", "multiset", "bloom", "sketch", "topk", "bitmap", "ratelimit", "stream",
+];
+
+// Where the socket reports failed security handshakes.
+const MONITOR_ENDPOINT: &str = "inproc://kv-client-monitor";
+
+type Reply = Result<cm::wire_message::Inner, RequestError>;
+
+/// How the client proves itself to the server; see the server's `Auth`.
+pub enum Credentials {
+    None,
+    Token { user: String, token: String },
+    Curve { keypair: KeyPair, server_key: Vec<u8> },
+}
+
+impl Credentials {
+    // Sets up the socket to use these before it connects.
+    fn configure(&self, socket: &zmq::Socket) -> Result<(), zmq::Error> {
+        match self {
+            Credentials::None => Ok(()),
+            Credentials::Token { user, token } => {
+                socket.set_plain_username(Some(user))?;
+                socket.set_plain_password(Some(token))
+            }
+            Credentials::Curve { keypair, server_key } => {
+                socket.set_curve_serverkey(server_key)?;
+                socket.set_curve_publickey(&keypair.public)?;
+                socket.set_curve_secretkey(&keypair.secret)
+            }
+        }
+    }
+}
+
+enum Session {
+    // Waiting on the answer to the Hello with this sequence number.
+    Connecting { sequence: u64 },
+    Established { id: u32 },
+    // The server turned us away, or the socket could not be set up; nothing more can be sent.
+    Failed,
+}
 
 pub struct MessengerServer {
     ctx: zmq::Context,
     host: String,
     port: u16,
     error_server_addr: Addr<ErrorServer>,
+    credentials: Credentials,
+    // What the receive loop sleeps on until `socket` or `monitor` have something for it, declared
+    // first so they're dropped before the sockets are closed.
+    readiness: Option<Readiness>,
+    monitor_readiness: Option<Readiness>,
     socket: Option<zmq::Socket>,
-    id: Option<u32>,
+    // Reports failed security handshakes, which libzmq would otherwise retry quietly forever.
+    monitor: Option<zmq::Socket>,
+    // Where the session's set and multiset requests go; the default namespace if empty.
+    namespace: String,
+    session: Session,
+    // Numbers each request, so replies can be matched up with the requests they answer.
+    sequence: u64,
+    // The most requests allowed on the wire at once.
+    window: usize,
+    // Requests waiting for room in the window, or for the session to be established.
+    queue: VecDeque<(m::wire_message::Inner, oneshot::Sender<Reply>)>,
+    // Requests sent and not yet answered, by sequence number.
+    in_flight: HashMap<u64, oneshot::Sender<Reply>>,
 }
 
 impl MessengerServer {
-    pub fn new(host: &str, port: u16, error_server_addr: Addr<ErrorServer>) -> Self {
+    pub fn new(
+        host: &str,
+        port: u16,
+        namespace: &str,
+        window: usize,
+        credentials: Credentials,
+        error_server_addr: Addr<ErrorServer>,
+    ) -> Self {
         MessengerServer {
-            id: Some(0), // TODO: Need to get an ID somehow.
             ctx: zmq::Context::new(),
             host: host.to_owned(),
             port,
             error_server_addr,
+            credentials,
+            readiness: None,
+            monitor_readiness: None,
             socket: None,
+            monitor: None,
+            namespace: namespace.to_owned(),
+            session: Session::Connecting { sequence: 0 },
+            sequence: 0,
+            window: window.max(1),
+            queue: VecDeque::new(),
+            in_flight: HashMap::new(),
         }
     }
 }
 
-// TODO: Need to get logic around collisions.
-// Client sends UUID + timestamp as connection ID.
-
 impl Actor for MessengerServer {
     type Context = Context<Self>;
 
-    fn started(&mut self, _ctx: &mut Self::Context) {
-        match self.ctx.socket(zmq::SocketType::REQ) {
-            Err(error) => self.error_server_addr.do_send(SocketOpenError(error)),
+    // Every error below fails the session, so requests don't wait on a socket that will never
+    // carry them.
+    fn started(&mut self, ctx: &mut Self::Context) {
+        match self.ctx.socket(zmq::SocketType::DEALER) {
+            Err(error) => {
+                self.error_server_addr.do_send(SocketOpenError(error));
+                self.fail();
+            }
             Ok(socket) => {
+                if let Err(error) = self.secure(&socket) {
+                    self.error_server_addr.do_send(SocketSecurityError(error));
+                    return self.fail();
+                }
                 self.socket = Some(socket);
                 if let Err(error) = self
                     .socket
@@ -48,62 +148,849 @@ impl Actor for MessengerServer {
                         error,
                         host: self.host.clone(),
                         port: self.port,
-                    })
+                    });
+                    return self.fail();
+                }
+
+                let readiness = Readiness::new(self.socket.as_ref().unwrap()).and_then(|readiness| {
+                    let monitor_readiness = self.monitor.as_ref().map(Readiness::new).transpose()?;
+                    Ok((readiness, monitor_readiness))
+                });
+                match readiness {
+                    Ok((readiness, monitor_readiness)) => {
+                        self.readiness = Some(readiness);
+                        self.monitor_readiness = monitor_readiness;
+                        self.hello();
+                        ctx.notify(Recv);
+                    }
+                    Err(error) => {
+                        self.error_server_addr.do_send(SocketWaitError {
+                            error,
+                            host: self.host.clone(),
+                            port: self.port,
+                        });
+                        self.fail();
+                    }
                 }
             }
         }
     }
 }
 
-pub enum SetInsertError {
-    Retry,
+pub enum RequestError {
+    WrongType,
     Unexpected,
 }
 
-#[derive(actix::Message)]
-#[rtype(result = "Result<(), SetInsertError>")]
-pub struct SetInsert {
-    pub name: String,
-    pub value: Vec<u8>,
+// Logs a response that doesn't match the request it was sent for.
+fn unexpected<T>(
+    error_server_addr: &Addr<ErrorServer>,
+    inner: cm::wire_message::Inner,
+) -> Result<T, RequestError> {
+    let message = cm::WireMessage {
+        inner: Some(inner),
+        ..Default::default()
+    };
+
+    let mut buf = vec![];
+    buf.reserve(message.encoded_len());
+    message.encode(&mut buf).unwrap();
+
+    error_server_addr.do_send(MessageDecodeError(None, buf));
+    Err(RequestError::Unexpected)
 }
 
-impl SetInsert {
-    fn encode(self, id: u32) -> Vec<u8> {
+impl MessengerServer {
+    // Sets up the socket to present our credentials, and to report back if they're turned down.
+    fn secure(&mut self, socket: &zmq::Socket) -> Result<(), zmq::Error> {
+        self.credentials.configure(socket)?;
+        if let Credentials::None = self.credentials {
+            return Ok(());
+        }
+
+        let events = zmq::SocketEvent::HANDSHAKE_FAILED_AUTH as i32
+            | zmq::SocketEvent::HANDSHAKE_FAILED_PROTOCOL as i32;
+        socket.monitor(MONITOR_ENDPOINT, events)?;
+        let monitor = self.ctx.socket(zmq::SocketType::PAIR)?;
+        monitor.connect(MONITOR_ENDPOINT)?;
+        self.monitor = Some(monitor);
+        Ok(())
+    }
+
+    // Gives up on the session if the security handshake failed, since it will only fail again.
+    fn check_handshake(&mut self) {
+        let frames = match &self.monitor {
+            Some(monitor) => match monitor.recv_multipart(zmq::DONTWAIT) {
+                Ok(frames) => frames,
+                Err(_) => return,
+            },
+            None => return,
+        };
+        // The first frame starts with the event, as 16 bits in native byte order.
+        let event = match frames.first() {
+            Some(frame) if frame.len() >= 2 => {
+                zmq::SocketEvent::from_raw(u16::from_ne_bytes([frame[0], frame[1]]))
+            }
+            _ => return,
+        };
+
+        self.error_server_addr.do_send(HandshakeFailedError {
+            refused: event == zmq::SocketEvent::HANDSHAKE_FAILED_AUTH,
+            host: self.host.clone(),
+            port: self.port,
+        });
+        self.monitor_readiness = None;
+        self.monitor = None;
+        self.fail();
+    }
+
+    fn send(&mut self, inner: m::wire_message::Inner, flags: i32) -> Result<u64, zmq::Error> {
+        let id = match self.session {
+            Session::Established { id } => id,
+            _ => 0,
+        };
         let message = m::WireMessage {
             id,
-            inner: Some(m::wire_message::Inner::SetInsert(m::SetInsert {
-                name: self.name,
-                value: self.value,
-            })),
+            sequence: self.sequence + 1,
+            // The session already says which namespace to use.
+            namespace: String::new(),
+            inner: Some(inner),
         };
 
         let mut buf = vec![];
         buf.reserve(message.encoded_len());
         message.encode(&mut buf).unwrap();
-        buf
+
+        let sent = self.socket.as_ref().unwrap().send(buf, flags);
+        if let Some(readiness) = &self.readiness {
+            readiness.sent();
+        }
+        sent?;
+        self.sequence += 1;
+        Ok(self.sequence)
     }
-}
 
-impl Handler<SetInsert> for MessengerServer {
-    type Result = Result<(), SetInsertError>;
-
-    fn handle(&mut self, set_add: SetInsert, _ctx: &mut Context<Self>) -> Self::Result {
-        match self
-            .socket
-            .as_ref()
-            .unwrap()
-            .send(set_add.encode(self.id.unwrap()), zmq::DONTWAIT) // TODO: Better checking around IDs.
-        {
-            Err(zmq::Error::EAGAIN) => Err(SetInsertError::Retry),
+    // Asks the server for a session ID, which every other request must carry, and finds out
+    // which capabilities it supports.  Nothing else is sent until the answer comes back.
+    fn hello(&mut self) {
+        let inner = m::wire_message::Inner::Hello(m::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_name: format!("kv {}", env!("CARGO_PKG_VERSION")),
+            capabilities: CAPABILITIES.iter().map(|capability| capability.to_string()).collect(),
+            namespace: self.namespace.clone(),
+        });
+        match self.send(inner, 0) {
+            Ok(sequence) => self.session = Session::Connecting { sequence },
             Err(error) => {
                 self.error_server_addr.do_send(SocketSendError {
                     error,
                     host: self.host.clone(),
                     port: self.port,
                 });
-                Err(SetInsertError::Unexpected)
+                self.fail();
+            }
+        }
+    }
+
+    fn welcome(&mut self, inner: cm::wire_message::Inner) {
+        match inner {
+            cm::wire_message::Inner::Welcome(cm::Welcome {
+                session_id,
+                capabilities,
+                ..
+            }) => {
+                self.session = Session::Established { id: session_id };
+
+                // Not fatal, as long as we don't need them; but if we do, this explains why.
+                let missing: Vec<String> = CAPABILITIES
+                    .iter()
+                    .filter(|capability| !capabilities.iter().any(|granted| granted == *capability))
+                    .map(|capability| capability.to_string())
+                    .collect();
+                if !missing.is_empty() {
+                    self.error_server_addr.do_send(MissingCapabilitiesError(missing));
+                }
+            }
+            // Already logged when the response came in.
+            cm::wire_message::Inner::UnsupportedVersion(_) | cm::wire_message::Inner::Error(_) => {
+                self.fail()
+            }
+            other => {
+                let _ = unexpected::<()>(&self.error_server_addr, other);
+                self.fail();
+            }
+        }
+    }
+
+    // Gives up on the session, failing every request still waiting on it.
+    fn fail(&mut self) {
+        self.session = Session::Failed;
+        for (_, reply) in self.queue.drain(..) {
+            let _ = reply.send(Err(RequestError::Unexpected));
+        }
+        for (_, reply) in self.in_flight.drain() {
+            let _ = reply.send(Err(RequestError::Unexpected));
+        }
+    }
+
+    // Whether queued requests are waiting only on the socket having room for them.
+    fn wants_to_send(&self) -> bool {
+        match self.session {
+            Session::Established { .. } => !self.queue.is_empty() && self.in_flight.len() < self.window,
+            _ => false,
+        }
+    }
+
+    // Sends queued requests for as long as there's room in the window.  Requests the socket can't
+    // take right now stay queued, to be retried once it has room.
+    fn flush(&mut self) {
+        if let Session::Established { .. } = self.session {
+            while self.in_flight.len() < self.window {
+                let (inner, reply) = match self.queue.pop_front() {
+                    None => break,
+                    Some(request) => request,
+                };
+                match self.send(inner.clone(), zmq::DONTWAIT) {
+                    Ok(sequence) => {
+                        self.in_flight.insert(sequence, reply);
+                    }
+                    Err(zmq::Error::EAGAIN) => {
+                        self.queue.push_front((inner, reply));
+                        // The receive loop waits for room now, too.
+                        if let Some(readiness) = &self.readiness {
+                            readiness.sent();
+                        }
+                        break;
+                    }
+                    Err(error) => {
+                        self.error_server_addr.do_send(SocketSendError {
+                            error,
+                            host: self.host.clone(),
+                            port: self.port,
+                        });
+                        let _ = reply.send(Err(RequestError::Unexpected));
+                    }
+                }
+            }
+        }
+    }
+
+    // Queues a request; the returned future resolves with the server's answer.
+    fn request(&mut self, inner: m::wire_message::Inner) -> impl std::future::Future<Output = Reply> {
+        let (sender, receiver) = oneshot::channel();
+        match self.session {
+            Session::Failed => {
+                let _ = sender.send(Err(RequestError::Unexpected));
+            }
+            _ => {
+                self.queue.push_back((inner, sender));
+                self.flush();
+            }
+        }
+        async move { receiver.await.unwrap_or(Err(RequestError::Unexpected)) }
+    }
+
+    // Sends a request and picks the result out of the answer with `parse`, which hands back any
+    // answer it doesn't expect so it can be logged.
+    fn call<T, F>(
+        &mut self,
+        inner: m::wire_message::Inner,
+        parse: F,
+    ) -> ResponseFuture<Result<T, RequestError>>
+    where
+        T: 'static,
+        F: FnOnce(cm::wire_message::Inner) -> Result<T, cm::wire_message::Inner> + 'static,
+    {
+        let request = self.request(inner);
+        let error_server_addr = self.error_server_addr.clone();
+        Box::pin(async move {
+            match parse(request.await?) {
+                Ok(result) => Ok(result),
+                Err(other) => unexpected(&error_server_addr, other),
+            }
+        })
+    }
+
+    // Routes a response to whoever is waiting on it.
+    fn receive(&mut self, bytes: Vec<u8>) {
+        let (sequence, inner) = match cm::WireMessage::decode(Cursor::new(&bytes)) {
+            Ok(cm::WireMessage {
+                sequence,
+                inner: Some(inner),
+                ..
+            }) => (sequence, inner),
+            Ok(cm::WireMessage { inner: None, .. }) => {
+                return self.error_server_addr.do_send(MessageDecodeError(None, bytes));
+            }
+            Err(decode_error) => {
+                return self
+                    .error_server_addr
+                    .do_send(MessageDecodeError(Some(decode_error), bytes));
+            }
+        };
+
+        let reply = match &inner {
+            cm::wire_message::Inner::Error(cm::Error { code, message, request_id }) => {
+                let code = cm::ErrorCode::from_i32(*code);
+                self.error_server_addr.do_send(ServerError {
+                    code,
+                    message: message.clone(),
+                    request_id: *request_id,
+                });
+                match code {
+                    Some(cm::ErrorCode::WrongType) => Err(RequestError::WrongType),
+                    // The server lost track of us; get a new session so later requests go
+                    // through.  Requests already sent under the old one fail the same way.
+                    Some(cm::ErrorCode::UnknownSession) => {
+                        if let Session::Established { .. } = self.session {
+                            self.hello();
+                        }
+                        Err(RequestError::Unexpected)
+                    }
+                    _ => Err(RequestError::Unexpected),
+                }
+            }
+            cm::wire_message::Inner::UnsupportedVersion(cm::UnsupportedVersion {
+                min_version,
+                max_version,
+            }) => {
+                self.error_server_addr.do_send(UnsupportedVersionError {
+                    version: PROTOCOL_VERSION,
+                    min_version: *min_version,
+                    max_version: *max_version,
+                });
+                Err(RequestError::Unexpected)
             }
             _ => Ok(()),
+        };
+
+        match self.session {
+            Session::Connecting { sequence: hello } if hello == sequence => self.welcome(inner),
+            _ => match self.in_flight.remove(&sequence) {
+                Some(sender) => {
+                    let _ = sender.send(reply.map(|()| inner));
+                }
+                // Sequence numbers start at 1; 0 means the server couldn't tell which request it
+                // was answering, e.g. because it couldn't decode it, and that was logged above.
+                None if sequence == 0 => (),
+                None => self.error_server_addr.do_send(UnmatchedResponseError { sequence }),
+            },
+        }
+    }
+}
+
+#[derive(actix::Message)]
+#[rtype(result = "()")]
+struct Recv;
+
+impl Handler<Recv> for MessengerServer {
+    type Result = ();
+
+    // The main read loop of this actor.  Takes in every response that has arrived, then sends
+    // whatever the window has room for now, then sleeps until there's more to do.
+    fn handle(&mut self, _: Recv, ctx: &mut Context<Self>) -> Self::Result {
+        loop {
+            match self.socket.as_ref().unwrap().recv_bytes(zmq::DONTWAIT) {
+                Ok(bytes) => self.receive(bytes),
+                // Nothing more to read for now.
+                Err(zmq::Error::EAGAIN) | Err(zmq::Error::EINTR) => break,
+                Err(error) => {
+                    self.error_server_addr.do_send(SocketRecvError {
+                        error,
+                        host: self.host.clone(),
+                        port: self.port,
+                    });
+                    self.fail();
+                    return ctx.stop();
+                }
+            }
         }
+        self.check_handshake();
+        self.flush();
+
+        ctx.spawn(Ready.map(|result, act, ctx| match result {
+            Ok(()) => ctx.notify(Recv),
+            Err(error) => {
+                act.error_server_addr.do_send(SocketWaitError {
+                    error,
+                    host: act.host.clone(),
+                    port: act.port,
+                });
+                act.fail();
+                ctx.stop();
+            }
+        }));
+    }
+}
+
+// Resolves once there's a response to read, a failed handshake to report, or room to send queued
+// requests.
+struct Ready;
+
+impl ActorFuture for Ready {
+    type Output = io::Result<()>;
+    type Actor = MessengerServer;
+
+    fn poll(
+        self: Pin<&mut Self>,
+        act: &mut MessengerServer,
+        _ctx: &mut Context<MessengerServer>,
+        task: &mut task::Context,
+    ) -> Poll<Self::Output> {
+        if let (Some(monitor), Some(readiness)) = (&act.monitor, &act.monitor_readiness) {
+            if let Poll::Ready(result) = readiness.poll_readable(monitor, task) {
+                return Poll::Ready(result);
+            }
+        }
+        let events = if act.wants_to_send() { zmq::POLLIN | zmq::POLLOUT } else { zmq::POLLIN };
+        match (&act.socket, &act.readiness) {
+            (Some(socket), Some(readiness)) => readiness.poll_events(socket, events, task),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+/// Inserts a value into a set; resolves to true if the value was not already a member.
+#[derive(actix::Message)]
+#[rtype(result = "Result<bool, RequestError>")]
+pub struct SetInsert {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+impl Handler<SetInsert> for MessengerServer {
+    type Result = ResponseFuture<Result<bool, RequestError>>;
+
+    fn handle(&mut self, SetInsert { name, value }: SetInsert, _ctx: &mut Context<Self>) -> Self::Result {
+        let inner = m::wire_message::Inner::SetInsert(m::SetInsert { name, value });
+        self.call(inner, |reply| match reply {
+            cm::wire_message::Inner::SetInsertResult(cm::SetInsertResult { inserted }) => Ok(inserted),
+            other => Err(other),
+        })
+    }
+}
+
+/// Inserts many values into a set at once; resolves to whether each one was not already a member.
+#[derive(actix::Message)]
+#[rtype(result = "Result<Vec<bool>, RequestError>")]
+pub struct SetInsertMany {
+    pub name: String,
+    pub values: Vec<Vec<u8>>,
+}
+
+impl Handler<SetInsertMany> for MessengerServer {
+    type Result = ResponseFuture<Result<Vec<bool>, RequestError>>;
+
+    fn handle(&mut self, SetInsertMany { name, values }: SetInsertMany, _ctx: &mut Context<Self>) -> Self::Result {
+        let count = values.len();
+        let inner = m::wire_message::Inner::SetInsertMany(m::SetInsertMany { name, values });
+        self.call(inner, move |reply| match reply {
+            cm::wire_message::Inner::SetInsertManyResult(cm::SetInsertManyResult { inserted })
+                if inserted.len() == (count + 7) / 8 =>
+            {
+                Ok((0..count).map(|i| inserted[i / 8] & (1 << (i % 8)) != 0).collect())
+            }
+            other => Err(other),
+        })
+    }
+}
+
+/// Fetches a page of a set scan; resolves to the members and the cursor for the next page, which
+/// is empty once the scan is complete.
+#[derive(actix::Message)]
+#[rtype(result = "Result<(Vec<Vec<u8>>, Vec<u8>), RequestError>")]
+pub struct SetScan {
+    pub name: String,
+    pub cursor: Vec<u8>,
+    pub count: u32,
+    pub prefix: Vec<u8>,
+    pub pattern: Vec<u8>,
+}
+
+impl Handler<SetScan> for MessengerServer {
+    type Result = ResponseFuture<Result<(Vec<Vec<u8>>, Vec<u8>), RequestError>>;
+
+    fn handle(
+        &mut self,
+        SetScan { name, cursor, count, prefix, pattern }: SetScan,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        let inner = m::wire_message::Inner::SetScan(m::SetScan {
+            name,
+            cursor,
+            count,
+            prefix,
+            pattern,
+        });
+        self.call(inner, |reply| match reply {
+            cm::wire_message::Inner::SetScanResult(cm::SetScanResult { members, cursor }) => {
+                Ok((members, cursor))
+            }
+            other => Err(other),
+        })
+    }
+}
+
+/// Lists the names of the sets and multisets in the namespace, only those matching the glob
+/// `pattern` if it isn't empty.
+#[derive(actix::Message)]
+#[rtype(result = "Result<Vec<String>, RequestError>")]
+pub struct ListNames {
+    pub pattern: Vec<u8>,
+}
+
+impl Handler<ListNames> for MessengerServer {
+    type Result = ResponseFuture<Result<Vec<String>, RequestError>>;
+
+    fn handle(&mut self, ListNames { pattern }: ListNames, _ctx: &mut Context<Self>) -> Self::Result {
+        let inner = m::wire_message::Inner::ListNames(m::ListNames { pattern });
+        self.call(inner, |reply| match reply {
+            cm::wire_message::Inner::ListNamesResult(cm::ListNamesResult { names }) => Ok(names),
+            other => Err(other),
+        })
+    }
+}
+
+/// Removes every set and multiset in the namespace; resolves to how many there were.
+#[derive(actix::Message)]
+#[rtype(result = "Result<u64, RequestError>")]
+pub struct FlushNamespace;
+
+impl Handler<FlushNamespace> for MessengerServer {
+    type Result = ResponseFuture<Result<u64, RequestError>>;
+
+    fn handle(&mut self, _: FlushNamespace, _ctx: &mut Context<Self>) -> Self::Result {
+        let inner = m::wire_message::Inner::FlushNamespace(m::FlushNamespace {});
+        self.call(inner, |reply| match reply {
+            cm::wire_message::Inner::FlushNamespaceResult(cm::FlushNamespaceResult { removed }) => Ok(removed),
+            other => Err(other),
+        })
+    }
+}
+
+/// Limits how many members the namespace can hold, with 0 lifting the limit; resolves to how many
+/// it holds now.
+#[derive(actix::Message)]
+#[rtype(result = "Result<u64, RequestError>")]
+pub struct SetQuota {
+    pub max_members: u64,
+}
+
+impl Handler<SetQuota> for MessengerServer {
+    type Result = ResponseFuture<Result<u64, RequestError>>;
+
+    fn handle(&mut self, SetQuota { max_members }: SetQuota, _ctx: &mut Context<Self>) -> Self::Result {
+        let inner = m::wire_message::Inner::SetQuota(m::SetQuota { max_members });
+        self.call(inner, |reply| match reply {
+            cm::wire_message::Inner::SetQuotaResult(cm::SetQuotaResult { members }) => Ok(members),
+            other => Err(other),
+        })
+    }
+}
+
+/// Has the set or multiset removed once `ttl_ms` milliseconds have passed, with 0 keeping it
+/// indefinitely again; resolves to false if there is no such set or multiset.
+#[derive(actix::Message)]
+#[rtype(result = "Result<bool, RequestError>")]
+pub struct SetExpire {
+    pub name: String,
+    pub ttl_ms: u64,
+}
+
+impl Handler<SetExpire> for MessengerServer {
+    type Result = ResponseFuture<Result<bool, RequestError>>;
+
+    fn handle(&mut self, SetExpire { name, ttl_ms }: SetExpire, _ctx: &mut Context<Self>) -> Self::Result {
+        let inner = m::wire_message::Inner::SetExpire(m::SetExpire { name, ttl_ms });
+        self.call(inner, |reply| match reply {
+            cm::wire_message::Inner::SetExpireResult(cm::SetExpireResult { set }) => Ok(set),
+            other => Err(other),
+        })
+    }
+}
+
+/// Creates a set holding at most `max_members`; resolves to false if one by that name already
+/// existed.
+#[derive(actix::Message)]
+#[rtype(result = "Result<bool, RequestError>")]
+pub struct SetCreate {
+    pub name: String,
+    pub max_members: u64,
+    pub overflow: Overflow,
+}
+
+impl Handler<SetCreate> for MessengerServer {
+    type Result = ResponseFuture<Result<bool, RequestError>>;
+
+    fn handle(
+        &mut self,
+        SetCreate { name, max_members, overflow }: SetCreate,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        let overflow = match overflow {
+            Overflow::Reject => m::Overflow::Reject,
+            Overflow::EvictOldest => m::Overflow::EvictOldest,
+            Overflow::EvictRandom => m::Overflow::EvictRandom,
+        };
+        let inner = m::wire_message::Inner::SetCreate(m::SetCreate {
+            name,
+            max_members,
+            overflow: overflow as i32,
+        });
+        self.call(inner, |reply| match reply {
+            cm::wire_message::Inner::SetCreateResult(cm::SetCreateResult { created }) => Ok(created),
+            other => Err(other),
+        })
+    }
+}
+
+/// Creates a Bloom filter; resolves to false if one by that name already existed.
+#[derive(actix::Message)]
+#[rtype(result = "Result<bool, RequestError>")]
+pub struct BfReserve {
+    pub name: String,
+    pub capacity: u64,
+    pub error_rate: f64,
+}
+
+impl Handler<BfReserve> for MessengerServer {
+    type Result = ResponseFuture<Result<bool, RequestError>>;
+
+    fn handle(
+        &mut self,
+        BfReserve { name, capacity, error_rate }: BfReserve,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        let inner = m::wire_message::Inner::BfReserve(m::BfReserve {
+            name,
+            capacity,
+            error_rate,
+        });
+        self.call(inner, |reply| match reply {
+            cm::wire_message::Inner::BfReserveResult(cm::BfReserveResult { created }) => Ok(created),
+            other => Err(other),
+        })
+    }
+}
+
+/// Adds a value to a Bloom filter; resolves to false if the value was probably already added.
+#[derive(actix::Message)]
+#[rtype(result = "Result<bool, RequestError>")]
+pub struct BfAdd {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+impl Handler<BfAdd> for MessengerServer {
+    type Result = ResponseFuture<Result<bool, RequestError>>;
+
+    fn handle(&mut self, BfAdd { name, value }: BfAdd, _ctx: &mut Context<Self>) -> Self::Result {
+        let inner = m::wire_message::Inner::BfAdd(m::BfAdd { name, value });
+        self.call(inner, |reply| match reply {
+            cm::wire_message::Inner::BfAddResult(cm::BfAddResult { added }) => Ok(added),
+            other => Err(other),
+        })
+    }
+}
+
+/// Counts a value towards a top-K list; resolves to the value's new estimated count.
+#[derive(actix::Message)]
+#[rtype(result = "Result<u64, RequestError>")]
+pub struct TopKAdd {
+    pub name: String,
+    pub k: u32,
+    pub value: Vec<u8>,
+}
+
+impl Handler<TopKAdd> for MessengerServer {
+    type Result = ResponseFuture<Result<u64, RequestError>>;
+
+    fn handle(&mut self, TopKAdd { name, k, value }: TopKAdd, _ctx: &mut Context<Self>) -> Self::Result {
+        let inner = m::wire_message::Inner::TopKAdd(m::TopKAdd { name, k, value });
+        self.call(inner, |reply| match reply {
+            cm::wire_message::Inner::TopKAddResult(cm::TopKAddResult { count }) => Ok(count),
+            other => Err(other),
+        })
+    }
+}
+
+/// Fetches a top-K list as (value, count) pairs, most frequent first.
+#[derive(actix::Message)]
+#[rtype(result = "Result<Vec<(Vec<u8>, u64)>, RequestError>")]
+pub struct TopKList {
+    pub name: String,
+}
+
+impl Handler<TopKList> for MessengerServer {
+    type Result = ResponseFuture<Result<Vec<(Vec<u8>, u64)>, RequestError>>;
+
+    fn handle(&mut self, TopKList { name }: TopKList, _ctx: &mut Context<Self>) -> Self::Result {
+        let inner = m::wire_message::Inner::TopKList(m::TopKList { name });
+        self.call(inner, |reply| match reply {
+            cm::wire_message::Inner::TopKListResult(cm::TopKListResult { members }) => Ok(members
+                .into_iter()
+                .map(|cm::ValueCount { value, count }| (value, count))
+                .collect()),
+            other => Err(other),
+        })
+    }
+}
+
+/// Adds one to the multiplicity of a value; resolves to the new multiplicity.
+#[derive(actix::Message)]
+#[rtype(result = "Result<u64, RequestError>")]
+pub struct MultisetInsert {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+impl Handler<MultisetInsert> for MessengerServer {
+    type Result = ResponseFuture<Result<u64, RequestError>>;
+
+    fn handle(
+        &mut self,
+        MultisetInsert { name, value }: MultisetInsert,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        let inner = m::wire_message::Inner::MultisetInsert(m::MultisetInsert { name, value });
+        self.call(inner, |reply| match reply {
+            cm::wire_message::Inner::MultisetInsertResult(cm::MultisetInsertResult { count }) => Ok(count),
+            other => Err(other),
+        })
+    }
+}
+
+/// Fetches every member of a multiset as (value, multiplicity) pairs.
+#[derive(actix::Message)]
+#[rtype(result = "Result<Vec<(Vec<u8>, u64)>, RequestError>")]
+pub struct MultisetMembers {
+    pub name: String,
+}
+
+impl Handler<MultisetMembers> for MessengerServer {
+    type Result = ResponseFuture<Result<Vec<(Vec<u8>, u64)>, RequestError>>;
+
+    fn handle(&mut self, MultisetMembers { name }: MultisetMembers, _ctx: &mut Context<Self>) -> Self::Result {
+        let inner = m::wire_message::Inner::MultisetMembers(m::MultisetMembers { name });
+        self.call(inner, |reply| match reply {
+            cm::wire_message::Inner::MultisetMembersResult(cm::MultisetMembersResult { members }) => Ok(members
+                .into_iter()
+                .map(|cm::ValueCount { value, count }| (value, count))
+                .collect()),
+            other => Err(other),
+        })
+    }
+}
+
+/// Takes tokens from a rate limiter; resolves to whether they were granted, and if not, how long
+/// to wait before asking again.
+#[derive(actix::Message)]
+#[rtype(result = "Result<(bool, Duration), RequestError>")]
+pub struct Acquire {
+    pub name: String,
+    pub rate: f64,
+    pub burst: u64,
+    pub cost: u64,
+    pub block: bool,
+}
+
+impl Handler<Acquire> for MessengerServer {
+    type Result = ResponseFuture<Result<(bool, Duration), RequestError>>;
+
+    fn handle(
+        &mut self,
+        Acquire { name, rate, burst, cost, block }: Acquire,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        let inner = m::wire_message::Inner::Acquire(m::Acquire {
+            name,
+            rate,
+            burst,
+            cost,
+            block,
+        });
+        self.call(inner, |reply| match reply {
+            cm::wire_message::Inner::AcquireResult(cm::AcquireResult { allowed, retry_after_ms }) => {
+                Ok((allowed, Duration::from_millis(retry_after_ms)))
+            }
+            other => Err(other),
+        })
+    }
+}
+
+/// Appends a value to a stream; resolves to the ID the server assigned it.
+#[derive(actix::Message)]
+#[rtype(result = "Result<u64, RequestError>")]
+pub struct StreamAppend {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+impl Handler<StreamAppend> for MessengerServer {
+    type Result = ResponseFuture<Result<u64, RequestError>>;
+
+    fn handle(&mut self, StreamAppend { name, value }: StreamAppend, _ctx: &mut Context<Self>) -> Self::Result {
+        let inner = m::wire_message::Inner::StreamAppend(m::StreamAppend { name, value });
+        self.call(inner, |reply| match reply {
+            cm::wire_message::Inner::StreamAppendResult(cm::StreamAppendResult { id }) => Ok(id),
+            other => Err(other),
+        })
+    }
+}
+
+/// Reads entries from a stream as (ID, value) pairs; see the server's `StreamRead` message.
+#[derive(actix::Message)]
+#[rtype(result = "Result<Vec<(u64, Vec<u8>)>, RequestError>")]
+pub struct StreamRead {
+    pub name: String,
+    pub group: Option<String>,
+    pub after: u64,
+    pub count: u32,
+    pub block_ms: u64,
+}
+
+impl Handler<StreamRead> for MessengerServer {
+    type Result = ResponseFuture<Result<Vec<(u64, Vec<u8>)>, RequestError>>;
+
+    fn handle(
+        &mut self,
+        StreamRead { name, group, after, count, block_ms }: StreamRead,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        let inner = m::wire_message::Inner::StreamRead(m::StreamRead {
+            name,
+            group: group.unwrap_or_default(),
+            after,
+            count,
+            block_ms,
+        });
+        self.call(inner, |reply| match reply {
+            cm::wire_message::Inner::StreamReadResult(cm::StreamReadResult { entries }) => Ok(entries
+                .into_iter()
+                .map(|cm::StreamEntry { id, value }| (id, value))
+                .collect()),
+            other => Err(other),
+        })
+    }
+}
+
+/// Commits a consumer group's offset; resolves to the group's committed ID afterwards.
+#[derive(actix::Message)]
+#[rtype(result = "Result<u64, RequestError>")]
+pub struct StreamCommit {
+    pub name: String,
+    pub group: String,
+    pub id: u64,
+}
+
+impl Handler<StreamCommit> for MessengerServer {
+    type Result = ResponseFuture<Result<u64, RequestError>>;
+
+    fn handle(&mut self, StreamCommit { name, group, id }: StreamCommit, _ctx: &mut Context<Self>) -> Self::Result {
+        let inner = m::wire_message::Inner::StreamCommit(m::StreamCommit { name, group, id });
+        self.call(inner, |reply| match reply {
+            cm::wire_message::Inner::StreamCommitResult(cm::StreamCommitResult { id }) => Ok(id),
+            other => Err(other),
+        })
     }
 }
@@ -1,21 +1,63 @@
-use std::collections::VecDeque;
-use std::io::{self, Read};
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::sync::mpsc;
+use std::thread;
 
-use actix::prelude::SendError;
-use actix::{Actor, Addr, Context, Handler, Message};
+use actix::{Actor, ActorFuture, Addr, AsyncContext, Context, Handler, Message, WrapFuture};
+use tokio::sync::oneshot;
 
-use crate::client::errors::{ErrorServer, StdinReadError};
-use crate::client::messenger::{MessengerServer, SetInsert};
+use crate::client::errors::{ErrorServer, StdinReadError, StdoutWriteError};
+use crate::client::messenger::{
+    BfAdd, MessengerServer, MultisetInsert, SetInsertMany, StreamAppend, TopKAdd,
+};
+
+// Chunks headed for a set are sent in batches of at most this many chunks, or this many bytes,
+// whichever comes first; a chunk bigger than that goes in a batch of its own.
+const BATCH_COUNT: usize = 1000;
+const BATCH_BYTES: usize = 1 << 20;
+
+// The most bytes taken from stdin in one read.
+const READ_SIZE: usize = 5242880;
+
+/// Where each chunk read from stdin goes.
+pub enum Sink {
+    /// Inserted into a set; new chunks are echoed to stdout.
+    Set,
+    /// Added to a Bloom filter; new chunks are echoed to stdout.
+    Bloom,
+    /// Counted towards a top-K list keeping `k` members.
+    TopK { k: u32 },
+    /// Counted in a multiset.
+    Multiset,
+    /// Appended to a stream.
+    Stream,
+}
 
 pub struct StdinReaderServer {
     current_chunk: Vec<u8>,
     current_sep_idx: usize,
-    buf: [u8; 5242880],
+    // Asks the thread reading stdin for the next read; see `started`.
+    reads: Option<mpsc::Sender<()>>,
     chunks: VecDeque<Vec<u8>>,
     error_server_addr: Addr<ErrorServer>,
     messenger_server_addr: Addr<MessengerServer>,
     name: String,
     sep: Vec<u8>,
+    sink: Sink,
+    // The most requests to have waiting on the server at once; stdin isn't read while there are
+    // this many.
+    window: usize,
+    in_flight: usize,
+    // Requests are numbered in the order they're sent, so anything they echo can be printed in
+    // the same order as the input, even when the responses come back out of order.
+    next_request: u64,
+    next_echo: u64,
+    echoes: BTreeMap<u64, Vec<Vec<u8>>>,
+    // Set when reading was put off because the window was full.
+    paused: bool,
+    eof: bool,
+    done: Option<oneshot::Sender<()>>,
 }
 
 impl StdinReaderServer {
@@ -24,34 +66,40 @@ impl StdinReaderServer {
         messenger_server_addr: Addr<MessengerServer>,
         name: String,
         sep: Vec<u8>,
+        sink: Sink,
+        window: usize,
+        done: oneshot::Sender<()>,
     ) -> StdinReaderServer {
         StdinReaderServer {
             current_chunk: vec![],
             current_sep_idx: 0,
-            buf: [0; 5242880],
+            reads: None,
             chunks: VecDeque::new(),
             error_server_addr,
             messenger_server_addr,
             name,
             sep,
+            sink,
+            window: window.max(1),
+            in_flight: 0,
+            next_request: 0,
+            next_echo: 0,
+            echoes: BTreeMap::new(),
+            paused: false,
+            eof: false,
+            done: Some(done),
         }
     }
 
-    fn parse_chunks(&mut self, bytes_read: usize) {
+    fn parse_chunks(&mut self, bytes: &[u8]) {
         if self.sep.len() == 0 {
-            for i in 0..bytes_read {
-                self.current_chunk.push(self.buf[i]);
-            }
-
-            self.buf = [0; 5242880];
-
+            self.current_chunk.extend_from_slice(bytes);
             return;
         }
 
         let last_sep_idx = self.sep.len() - 1;
 
-        for i in 0..bytes_read {
-            let datum = self.buf[i];
+        for &datum in bytes {
             if datum != self.sep[self.current_sep_idx] {
                 self.current_sep_idx = 0;
                 self.current_chunk.push(datum);
@@ -65,32 +113,150 @@ impl StdinReaderServer {
                 self.current_sep_idx += 1;
             }
         }
-
-        self.buf = [0; 5242880];
     }
 
-    fn flush_chunks(&mut self) {
-        while let Some(chunk) = self.chunks.pop_front() {
-            match self.messenger_server_addr.try_send(SetInsert {
-                name: self.name.clone(),
-                value: chunk,
-            }) {
-                Err(SendError::Full(SetInsert { name: _, value })) => {
-                    self.chunks.push_front(value);
-                    break;
+    // Sends parsed chunks to the server in order, for as long as there's room in the window.
+    fn flush_chunks(&mut self, ctx: &mut Context<Self>) {
+        while self.in_flight < self.window {
+            let chunk = match self.chunks.pop_front() {
+                None => break,
+                Some(chunk) => chunk,
+            };
+            let name = self.name.clone();
+            match self.sink {
+                Sink::Set => {
+                    let mut batch_bytes = chunk.len();
+                    let mut batch = vec![chunk];
+                    while batch.len() < BATCH_COUNT {
+                        match self.chunks.front() {
+                            Some(next) if batch_bytes + next.len() <= BATCH_BYTES => {
+                                batch_bytes += next.len();
+                                batch.push(self.chunks.pop_front().unwrap());
+                            }
+                            _ => break,
+                        }
+                    }
+
+                    let request = self.messenger_server_addr.send(SetInsertMany {
+                        name,
+                        values: batch.clone(),
+                    });
+                    // Failures were already reported by the messenger; there's nothing to print
+                    // for them.
+                    self.pipeline(ctx, async move {
+                        match request.await {
+                            Ok(Ok(inserted)) => batch
+                                .into_iter()
+                                .zip(inserted)
+                                .filter(|(_, inserted)| *inserted)
+                                .map(|(chunk, _)| chunk)
+                                .collect(),
+                            _ => vec![],
+                        }
+                    })
+                }
+                Sink::Bloom => {
+                    let request = self.messenger_server_addr.send(BfAdd { name, value: chunk.clone() });
+                    self.pipeline(ctx, async move {
+                        match request.await {
+                            Ok(Ok(true)) => vec![chunk],
+                            _ => vec![],
+                        }
+                    })
+                }
+                Sink::TopK { k } => {
+                    let request = self.messenger_server_addr.send(TopKAdd { name, k, value: chunk });
+                    self.pipeline(ctx, async move {
+                        let _ = request.await;
+                        vec![]
+                    })
+                }
+                Sink::Multiset => {
+                    let request = self.messenger_server_addr.send(MultisetInsert { name, value: chunk });
+                    self.pipeline(ctx, async move {
+                        let _ = request.await;
+                        vec![]
+                    })
                 }
-                Err(SendError::Closed(SetInsert { name: _, value })) => {
-                    self.chunks.push_front(value);
-                    break;
+                Sink::Stream => {
+                    let request = self.messenger_server_addr.send(StreamAppend { name, value: chunk });
+                    self.pipeline(ctx, async move {
+                        let _ = request.await;
+                        vec![]
+                    })
                 }
-                Ok(()) => (),
             }
         }
     }
+
+    // Waits on a request without holding up the next one; `request` resolves to the chunks to
+    // echo to stdout.
+    fn pipeline<F>(&mut self, ctx: &mut Context<Self>, request: F)
+    where
+        F: Future<Output = Vec<Vec<u8>>> + 'static,
+    {
+        let number = self.next_request;
+        self.next_request += 1;
+        self.in_flight += 1;
+        ctx.spawn(
+            request
+                .into_actor(self)
+                .map(move |echo, act, ctx| act.complete(number, echo, ctx)),
+        );
+    }
+
+    fn complete(&mut self, number: u64, echo: Vec<Vec<u8>>, ctx: &mut Context<Self>) {
+        self.in_flight -= 1;
+        self.echoes.insert(number, echo);
+        while let Some(echo) = self.echoes.remove(&self.next_echo) {
+            for chunk in echo {
+                self.emit(&chunk);
+            }
+            self.next_echo += 1;
+        }
+
+        // There's room in the window again: send what's left of the last read, then read more.
+        self.flush_chunks(ctx);
+        if !self.chunks.is_empty() {
+            return;
+        }
+        if self.eof {
+            if self.in_flight == 0 {
+                ctx.address().do_send(Finish);
+            }
+        } else if self.paused {
+            self.paused = false;
+            ctx.address().do_send(ProcessChunks);
+        }
+    }
+
+    // Echoes a chunk to stdout, followed by the separator.
+    fn emit(&self, chunk: &[u8]) {
+        let mut stdout = io::stdout();
+        if let Err(error) = stdout.write_all(chunk).and_then(|()| stdout.write_all(&self.sep)) {
+            self.error_server_addr.do_send(StdoutWriteError(error));
+        }
+    }
 }
 
 impl Actor for StdinReaderServer {
     type Context = Context<Self>;
+
+    // Reads stdin on a thread of its own, one read per request, so waiting on input never holds
+    // up responses or anything else running alongside.
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let (reads, requests) = mpsc::channel::<()>();
+        let addr = ctx.address();
+        thread::spawn(move || {
+            let mut buf = vec![0; READ_SIZE];
+            for () in requests {
+                let read = io::stdin().lock().read(&mut buf).map(|bytes_read| buf[..bytes_read].to_vec());
+                addr.do_send(Stdin(read));
+            }
+        });
+        self.reads = Some(reads);
+        ctx.address().do_send(ProcessChunks);
+    }
 }
 
 #[derive(Message)]
@@ -101,12 +267,47 @@ impl Handler<ProcessChunks> for StdinReaderServer {
     type Result = ();
 
     fn handle(&mut self, _: ProcessChunks, _ctx: &mut Context<Self>) -> Self::Result {
-        match io::stdin().lock().read(&mut self.buf) {
-            Ok(bytes_read) => {
-                if bytes_read > 0 {
-                    self.parse_chunks(bytes_read);
+        if let Some(reads) = &self.reads {
+            let _ = reads.send(());
+        }
+    }
+}
+
+// What one read of stdin got.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Stdin(io::Result<Vec<u8>>);
+
+impl Handler<Stdin> for StdinReaderServer {
+    type Result = ();
+
+    fn handle(&mut self, Stdin(read): Stdin, ctx: &mut Context<Self>) -> Self::Result {
+        match read {
+            Ok(bytes) => {
+                if !bytes.is_empty() {
+                    self.parse_chunks(&bytes);
+                    self.flush_chunks(ctx);
+                    // With the window full, hold off reading until responses make room for what
+                    // has already been read; see `complete`.
+                    if self.chunks.is_empty() {
+                        ctx.address().do_send(ProcessChunks);
+                    } else {
+                        self.paused = true;
+                    }
+                } else {
+                    // EOF; whatever is left over is the last chunk, separator or not.
+                    if self.current_chunk.len() > 0 {
+                        self.chunks.push_back(self.current_chunk.clone());
+                        self.current_chunk = vec![];
+                    }
+                    self.eof = true;
+                    // Lets the reading thread finish.
+                    self.reads = None;
+                    self.flush_chunks(ctx);
+                    if self.in_flight == 0 {
+                        ctx.address().do_send(Finish);
+                    }
                 }
-                self.flush_chunks();
             }
             Err(error) => {
                 // TODO: Find a better way to handle this error.
@@ -117,3 +318,18 @@ impl Handler<ProcessChunks> for StdinReaderServer {
         }
     }
 }
+
+// Sent once stdin is exhausted and every response is in.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Finish;
+
+impl Handler<Finish> for StdinReaderServer {
+    type Result = ();
+
+    fn handle(&mut self, _: Finish, _ctx: &mut Context<Self>) -> Self::Result {
+        if let Some(done) = self.done.take() {
+            let _ = done.send(());
+        }
+    }
+}
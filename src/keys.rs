@@ -0,0 +1,252 @@
+/// CURVE keypairs and shared tokens, read from the files given to the client and server.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use clap::Clap;
+
+// CURVE keys are 32 bytes, written out as 40 characters of Z85.
+const KEY_LEN: usize = 32;
+const ENCODED_KEY_LEN: usize = 40;
+
+#[derive(Clap)]
+pub struct Opts {
+    /// Where to write the new keypair.  Its public key is printed to stdout, to hand out to the
+    /// other side.
+    path: PathBuf,
+    /// Overwrite the file if it already exists.
+    #[clap(short, long)]
+    force: bool,
+}
+
+/// A CURVE keypair, in binary form.
+pub struct KeyPair {
+    pub public: Vec<u8>,
+    pub secret: Vec<u8>,
+}
+
+impl KeyPair {
+    pub fn generate() -> Result<KeyPair, zmq::Error> {
+        let zmq::CurveKeyPair { public_key, secret_key } = zmq::CurveKeyPair::new()?;
+        Ok(KeyPair {
+            public: public_key.to_vec(),
+            secret: secret_key.to_vec(),
+        })
+    }
+}
+
+pub enum KeyError {
+    Io { path: PathBuf, error: io::Error },
+    // The line doesn't hold what it should, or holds a key that isn't valid Z85.
+    Malformed { path: PathBuf, line: usize },
+    // A keypair file without one of its keys, or a token file with nothing in it.
+    Missing { path: PathBuf, what: &'static str },
+    // A second shared token, or a second token for the same user.
+    Duplicate { path: PathBuf, line: usize },
+    // A key given on the command line rather than in a file.
+    Invalid { key: String },
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyError::Io { path, error } => write!(f, "Could not read {}: {}", path.display(), error),
+            KeyError::Malformed { path, line } => {
+                write!(f, "Line {} of {} does not hold a valid key", line, path.display())
+            }
+            KeyError::Missing { path, what } => write!(f, "{} has no {}", path.display(), what),
+            KeyError::Duplicate { path, line } => {
+                write!(f, "Line {} of {} gives a second token to the same user", line, path.display())
+            }
+            KeyError::Invalid { key } => write!(f, "{:?} is not a valid CURVE key", key),
+        }
+    }
+}
+
+/// Decodes a key written out in Z85.
+pub fn decode(key: &str) -> Option<Vec<u8>> {
+    if key.len() != ENCODED_KEY_LEN || !key.is_ascii() {
+        return None;
+    }
+    zmq::z85_decode(key).ok().filter(|key| key.len() == KEY_LEN)
+}
+
+/// Writes a key out in Z85.
+pub fn encode(key: &[u8]) -> String {
+    // Keys are always a multiple of 4 bytes long, so this can't fail.
+    zmq::z85_encode(key).unwrap()
+}
+
+/// Decodes a key given on the command line.
+pub fn parse(key: &str) -> Result<Vec<u8>, KeyError> {
+    decode(key.trim()).ok_or_else(|| KeyError::Invalid { key: key.to_owned() })
+}
+
+fn read(path: &Path) -> Result<String, KeyError> {
+    fs::read_to_string(path).map_err(|error| KeyError::Io {
+        path: path.to_owned(),
+        error,
+    })
+}
+
+// The lines of a file worth looking at, numbered from 1; blank lines and comments are skipped.  A
+// comment is a # followed by a space or nothing else, since # on its own is a character of Z85 and
+// can start a key or token.
+fn lines(contents: &str) -> impl Iterator<Item = (usize, &str)> {
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && *line != "#" && !line.starts_with("# "))
+}
+
+/// Reads a keypair file written by `kv keygen`.
+pub fn read_keypair(path: &Path) -> Result<KeyPair, KeyError> {
+    let contents = read(path)?;
+    let (mut public, mut secret) = (None, None);
+    for (number, line) in lines(&contents) {
+        let malformed = || KeyError::Malformed {
+            path: path.to_owned(),
+            line: number,
+        };
+        let mut parts = line.splitn(2, '=').map(str::trim);
+        let slot = match parts.next() {
+            Some("public") => &mut public,
+            Some("secret") => &mut secret,
+            _ => return Err(malformed()),
+        };
+        *slot = Some(parts.next().and_then(decode).ok_or_else(malformed)?);
+    }
+
+    let missing = |what| KeyError::Missing {
+        path: path.to_owned(),
+        what,
+    };
+    Ok(KeyPair {
+        public: public.ok_or_else(|| missing("public key"))?,
+        secret: secret.ok_or_else(|| missing("secret key"))?,
+    })
+}
+
+/// Reads a file of public keys, one per line.
+pub fn read_public_keys(path: &Path) -> Result<Vec<Vec<u8>>, KeyError> {
+    let contents = read(path)?;
+    lines(&contents)
+        .map(|(number, line)| {
+            decode(line).ok_or_else(|| KeyError::Malformed {
+                path: path.to_owned(),
+                line: number,
+            })
+        })
+        .collect()
+}
+
+/// Reads a shared token, ignoring any whitespace around it.
+pub fn read_token(path: &Path) -> Result<String, KeyError> {
+    let token = read(path)?.trim().to_owned();
+    if token.is_empty() {
+        return Err(KeyError::Missing {
+            path: path.to_owned(),
+            what: "token",
+        });
+    }
+    Ok(token)
+}
+
+/// The tokens a server accepts.
+pub struct Tokens {
+    /// Accepted from any user without a token of their own.
+    pub shared: Option<String>,
+    /// Each user's own token.
+    pub users: HashMap<String, String>,
+}
+
+/// Reads a server's token file.  A line holding just a token is shared by every user; a line
+/// holding a user name and a token, separated by whitespace, is that user's own.
+pub fn read_tokens(path: &Path) -> Result<Tokens, KeyError> {
+    let contents = read(path)?;
+    let mut tokens = Tokens {
+        shared: None,
+        users: HashMap::new(),
+    };
+    for (number, line) in lines(&contents) {
+        let duplicate = || KeyError::Duplicate {
+            path: path.to_owned(),
+            line: number,
+        };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [token] => {
+                if tokens.shared.replace(token.to_string()).is_some() {
+                    return Err(duplicate());
+                }
+            }
+            [user, token] => {
+                if tokens.users.insert(user.to_string(), token.to_string()).is_some() {
+                    return Err(duplicate());
+                }
+            }
+            _ => {
+                return Err(KeyError::Malformed {
+                    path: path.to_owned(),
+                    line: number,
+                })
+            }
+        }
+    }
+
+    if tokens.shared.is_none() && tokens.users.is_empty() {
+        return Err(KeyError::Missing {
+            path: path.to_owned(),
+            what: "token",
+        });
+    }
+    Ok(tokens)
+}
+
+// Writes the keypair where only its owner can read it, since anyone holding the secret key can
+// pass themselves off as its owner.
+fn write_keypair(path: &Path, keypair: &KeyPair, force: bool) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(path)?;
+    // The mode only applies to a file being created, so one being overwritten is locked down
+    // before the secret goes in.
+    #[cfg(unix)]
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    write!(
+        file,
+        "# Written by kv keygen.  Keep this file secret; only the public key should be shared.\npublic = {}\nsecret = {}\n",
+        encode(&keypair.public),
+        encode(&keypair.secret)
+    )
+}
+
+/// Generates a new keypair, writes it to the given file, and prints its public key.
+pub fn keygen(opts: &Opts) {
+    let keypair = match KeyPair::generate() {
+        Ok(keypair) => keypair,
+        Err(error) => {
+            eprintln!("Could not generate a keypair: {}", error);
+            process::exit(1);
+        }
+    };
+    if let Err(error) = write_keypair(&opts.path, &keypair, opts.force) {
+        eprintln!("Could not write {}: {}", opts.path.display(), error);
+        process::exit(1);
+    }
+    println!("{}", encode(&keypair.public));
+}
@@ -2,25 +2,35 @@ extern crate actix;
 extern crate actix_rt;
 extern crate base64;
 extern crate clap;
+extern crate libc;
 extern crate log;
+extern crate mio;
+extern crate num_cpus;
 extern crate prost;
 extern crate prost_types;
+extern crate rand;
+extern crate roaring;
 extern crate simple_logger;
 extern crate tokio;
 extern crate zmq;
 
 mod client;
+mod keys;
 mod server;
 pub mod messages {
     include!(concat!(env!("OUT_DIR"), "/messages.rs"));
 }
 
+use std::process;
+
 use clap::Clap;
 
 #[derive(Clap)]
 enum Subcommands {
     Client(client::Opts),
     Server(server::Opts),
+    /// Generates a CURVE keypair for the client or server.
+    Keygen(keys::Opts),
 }
 
 #[derive(Clap)]
@@ -35,16 +45,25 @@ struct Opts {
     subcommand: Subcommands,
 }
 
-#[actix_rt::main]
-async fn main() {
+fn main() {
     let opts = Opts::parse();
+    // Before the runtime is up, since detaching forks and only this thread would carry on.
+    let detached = match &opts.subcommand {
+        Subcommands::Server(opts) => server::detach(opts),
+        _ => None,
+    };
 
-    match opts.subcommand {
-        Subcommands::Client(opts) => {
-            client::start(&opts).await;
-        }
-        Subcommands::Server(opts) => {
-            server::start(&opts).await;
+    actix_rt::System::new("main").block_on(async move {
+        match opts.subcommand {
+            Subcommands::Client(opts) => {
+                client::start(&opts).await;
+            }
+            Subcommands::Server(opts) => {
+                process::exit(server::start(&opts, detached).await);
+            }
+            Subcommands::Keygen(opts) => {
+                keys::keygen(&opts);
+            }
         }
-    }
+    })
 }
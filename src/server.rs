@@ -1,18 +1,45 @@
 /// Starts a persistent server which will give access to the concurrently accessed data structures.
-use actix::{Actor, System};
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::time::Duration;
+
+use actix::{Actor, Supervisor, System};
 use clap::Clap;
-use tokio::signal::ctrl_c;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
+use tokio::time::timeout;
 
-use errors::ErrorServer;
-use messenger::MessengerServer;
-use set::SetAgent;
+use crate::keys::{self, KeyError};
+use acl::Acl;
+use auth::Auth;
+use bitmap::BitmapAgent;
+use bloom::BloomAgent;
+use daemon::{Detached, Pidfile};
+use errors::{DrainTimeoutError, ErrorServer};
+use messenger::{Agents, Drain, Lifecycle, MessengerServer};
+use ratelimit::RateLimitAgent;
+use set::{EvictionPolicy, SetShards};
+use sketch::SketchAgent;
+use stream::StreamAgent;
 
+pub mod acl;
+pub mod auth;
+pub mod bitmap;
+pub mod bloom;
+pub mod daemon;
 pub mod errors;
+pub mod glob;
 pub mod messages {
     include!(concat!(env!("OUT_DIR"), "/server.messages.rs"));
 }
 pub mod messenger;
+pub mod ratelimit;
+pub mod readiness;
 pub mod set;
+pub mod sketch;
+pub mod stream;
 
 #[derive(Clap)]
 pub struct Opts {
@@ -20,14 +47,209 @@ pub struct Opts {
     host: String,
     #[clap(short, long, default_value = "60054")]
     port: u16,
+    /// Encrypts connections with the CURVE keypair in this file, written by `kv keygen`.
+    #[clap(long)]
+    curve_key: Option<PathBuf>,
+    /// Only lets in clients whose CURVE public keys are in this file, one per line.
+    #[clap(long, requires = "curve-key")]
+    curve_clients: Option<PathBuf>,
+    /// Only lets in clients presenting a token in this file: either a line holding a token anyone
+    /// can use, or lines holding a user name and that user's token.  Tokens cross the network in
+    /// the clear; use CURVE where that matters.
+    #[clap(long, conflicts_with = "curve-key")]
+    token_file: Option<PathBuf>,
+    /// Only lets clients do what the rules in this file allow; see `Acl::read` for the format.
+    #[clap(long)]
+    acl: Option<PathBuf>,
+    /// Limits how much memory sets and multisets can take up between them, in bytes or with a K,
+    /// M, G or T suffix, e.g. 512M.  The limit is split evenly between the --set-shards, and each
+    /// shard evicts or turns writes down once its own share is full, even if others have room.
+    #[clap(long)]
+    max_memory: Option<Bytes>,
+    /// What to do when a write would go past --max-memory: "reject" it, evict the least recently
+    /// used sets ("lru"), or evict the sets with a TTL first, soonest to expire first
+    /// ("ttl-first").
+    #[clap(long, default_value = "reject")]
+    eviction_policy: EvictionPolicy,
+    /// How many threads sets and multisets are split between, by a hash of their names.  Defaults
+    /// to one per CPU.
+    #[clap(long)]
+    set_shards: Option<usize>,
+    /// How long to wait on SIGTERM or SIGINT for requests already read to be answered before
+    /// exiting anyway, in seconds.
+    #[clap(long, default_value = "10")]
+    drain_timeout: u64,
+    /// Runs in the background, away from the terminal, once the server is taking requests.
+    #[clap(long)]
+    daemon: bool,
+    /// Writes the server's process ID to this file while it runs.
+    #[clap(long)]
+    pidfile: Option<PathBuf>,
+    /// Appends everything the server prints to this file.
+    #[clap(long)]
+    log_file: Option<PathBuf>,
+}
+
+// A size in bytes, optionally with a binary suffix.
+struct Bytes(usize);
+
+impl FromStr for Bytes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (digits, shift) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+            Some('K') => (&s[..s.len() - 1], 10),
+            Some('M') => (&s[..s.len() - 1], 20),
+            Some('G') => (&s[..s.len() - 1], 30),
+            Some('T') => (&s[..s.len() - 1], 40),
+            _ => (s, 0),
+        };
+        let invalid = || format!("{:?} is not a size like 1048576, 512K, 64M or 2G", s);
+        let n: usize = digits.parse().map_err(|_| invalid())?;
+        n.checked_mul(1 << shift).map(Bytes).ok_or_else(invalid)
+    }
+}
+
+fn auth(opts: &Opts) -> Result<Auth, KeyError> {
+    if let Some(path) = &opts.token_file {
+        return Ok(Auth::Tokens(keys::read_tokens(path)?));
+    }
+    match &opts.curve_key {
+        None => Ok(Auth::None),
+        Some(path) => Ok(Auth::Curve {
+            keypair: keys::read_keypair(path)?,
+            clients: match &opts.curve_clients {
+                None => None,
+                Some(path) => Some(keys::read_public_keys(path)?.into_iter().collect()),
+            },
+        }),
+    }
 }
 
-pub async fn start(opts: &Opts) {
+// Resolves once the process is asked to stop.
+async fn shutdown_signal() -> io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => (),
+        _ = interrupt.recv() => (),
+    }
+    Ok(())
+}
+
+/// Detaches from the terminal or sends output to the log file, as asked.  Has to be called before
+/// the runtime starts any threads.
+pub fn detach(opts: &Opts) -> Option<Detached> {
+    let detached = if opts.daemon {
+        daemon::detach(opts.log_file.as_deref()).map(Some)
+    } else {
+        opts.log_file.as_deref().map(daemon::redirect_output).transpose().map(|_| None)
+    };
+    match detached {
+        Ok(detached) => detached,
+        Err(error) => {
+            eprintln!("Could not detach the server or open its log file: {}", error);
+            // EX_OSERR from sysexits.h.
+            process::exit(71);
+        }
+    }
+}
+
+// Tells systemd, if it's listening, which isn't worth stopping the server over.
+fn notify(state: &str) {
+    if let Err(error) = daemon::notify(state) {
+        eprintln!("Could not tell systemd {:?}: {}", state, error);
+    }
+}
+
+/// Serves until asked to stop, or until it can't go on, returning the status to exit with.  Tells
+/// `detached` once the server is taking requests.
+pub async fn start(opts: &Opts, detached: Option<Detached>) -> i32 {
+    let auth = match auth(opts) {
+        Ok(auth) => auth,
+        Err(error) => {
+            eprintln!("{}", error);
+            // EX_CONFIG from sysexits.h.
+            return 78;
+        }
+    };
+    let acl = match &opts.acl {
+        None => None,
+        Some(path) => match Acl::read(path) {
+            Ok(acl) => Some(acl),
+            Err(error) => {
+                eprintln!("{}", error);
+                return 78;
+            }
+        },
+    };
+
+    let _pidfile = match &opts.pidfile {
+        None => None,
+        Some(path) => match Pidfile::create(path) {
+            Ok(pidfile) => Some(pidfile),
+            Err(error) => {
+                eprintln!("Could not write the pidfile {}: {}", path.display(), error);
+                // EX_CANTCREAT from sysexits.h.
+                return 73;
+            }
+        },
+    };
+
     let error_server = ErrorServer::new().start();
-    let set_agent = SetAgent::new().start();
-    let messenger_server = MessengerServer::new(&opts.host, opts.port, error_server, set_agent);
+    let error_server_addr = error_server.clone();
+    let agents = Agents {
+        set: SetShards::start(
+            opts.set_shards
+                .unwrap_or_else(num_cpus::get),
+            opts.max_memory.as_ref().map(|bytes| bytes.0),
+            opts.eviction_policy,
+        ),
+        bloom: BloomAgent::new().start(),
+        sketch: SketchAgent::new().start(),
+        bitmap: BitmapAgent::new().start(),
+        rate_limit: RateLimitAgent::new().start(),
+        stream: StreamAgent::new().start(),
+    };
+    let (ready, ready_receiver) = oneshot::channel();
+    let (failed, failed_receiver) = oneshot::channel();
+    let lifecycle = Lifecycle { ready, failed };
+    let messenger_server = MessengerServer::new(&opts.host, opts.port, auth, acl, error_server, agents, lifecycle);
+    let messenger_server = Supervisor::start(|_ctx| messenger_server);
+    actix_rt::spawn(async move {
+        if ready_receiver.await.is_ok() {
+            notify("READY=1");
+            if let Some(detached) = detached {
+                detached.ready();
+            }
+        }
+    });
 
-    // TODO: Is panicing appropriate here?
-    ctrl_c().await.unwrap();
+    // Returning rather than exiting straight away, so the pidfile is removed on the way out.
+    let signal = tokio::select! {
+        signal = shutdown_signal() => signal,
+        Ok(status) = failed_receiver => return status,
+    };
+    if let Err(error) = signal {
+        eprintln!("Could not listen for signals to shut down on: {}", error);
+        // EX_OSERR from sysexits.h.
+        return 71;
+    }
+    notify("STOPPING=1");
+    // Requests already read have been handed to the agents, which answer them in order, so once
+    // every reply has gone out there's nothing left queued for them.
+    let drain_timeout = Duration::from_secs(opts.drain_timeout);
+    let (drained, receiver) = oneshot::channel();
+    messenger_server.do_send(Drain(drained));
+    let status = match timeout(drain_timeout, receiver).await {
+        Ok(_) => 0,
+        Err(_) => {
+            let _ = error_server_addr.send(DrainTimeoutError(drain_timeout)).await;
+            // EX_TEMPFAIL from sysexits.h: some clients will have to try again.
+            75
+        }
+    };
     System::current().stop();
+    status
 }
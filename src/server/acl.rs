@@ -0,0 +1,221 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::keys;
+use crate::server::glob;
+
+/// What a request needs to be allowed to do.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Permission {
+    /// Look at a structure without changing it.
+    Read,
+    /// Change a structure.
+    Write,
+    /// Manage a whole namespace, e.g. flush it or set its quota.
+    Admin,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Permission::Read => write!(f, "read"),
+            Permission::Write => write!(f, "write"),
+            Permission::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+impl Permission {
+    fn parse(s: &str) -> Option<Permission> {
+        match s {
+            "read" => Some(Permission::Read),
+            "write" => Some(Permission::Write),
+            "admin" => Some(Permission::Admin),
+            _ => None,
+        }
+    }
+}
+
+// Who a rule covers.
+enum Users {
+    // The user names matching a glob.
+    Matching(Vec<u8>),
+    // The client with this CURVE public key, in Z85.  Keys are full of characters that mean
+    // something in a glob, so they're only ever compared as they are.
+    Key(String),
+}
+
+impl Users {
+    fn covers(&self, user: &str) -> bool {
+        match self {
+            Users::Matching(pattern) => glob::matches(pattern, user.as_bytes()),
+            Users::Key(key) => key == user,
+        }
+    }
+}
+
+// Grants the users covered by `users` the listed permissions on the structures matching `names` in
+// the namespaces matching `namespaces`, both globs.
+struct Rule {
+    users: Users,
+    permissions: Vec<Permission>,
+    namespaces: Vec<u8>,
+    names: Vec<u8>,
+}
+
+/// Who may do what to which structures.  Anything not granted by some rule is denied.
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+pub enum AclError {
+    Io { path: PathBuf, error: io::Error },
+    Malformed { path: PathBuf, line: usize },
+}
+
+impl fmt::Display for AclError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AclError::Io { path, error } => write!(f, "Could not read {}: {}", path.display(), error),
+            AclError::Malformed { path, line } => write!(
+                f,
+                "Line {} of {} is not of the form <users> <permissions> <namespaces> [<names>]",
+                line,
+                path.display()
+            ),
+        }
+    }
+}
+
+impl Acl {
+    /// Reads an ACL file, with one rule per line:
+    ///
+    ///     <users> <permissions> <namespaces> [<names>]
+    ///
+    /// where permissions are a comma-separated list of read, write and admin, and the rest are
+    /// globs, with names defaulting to `*`.  Users are a glob matching the user names clients
+    /// authenticate with using tokens, or `key:` followed by the public key a client authenticates
+    /// with using CURVE, which is matched exactly.  Blank lines and lines starting with # are
+    /// skipped.
+    pub fn read(path: &Path) -> Result<Acl, AclError> {
+        let contents = fs::read_to_string(path).map_err(|error| AclError::Io {
+            path: path.to_owned(),
+            error,
+        })?;
+        Acl::parse(path, &contents)
+    }
+
+    fn parse(path: &Path, contents: &str) -> Result<Acl, AclError> {
+        let mut rules = vec![];
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let malformed = || AclError::Malformed {
+                path: path.to_owned(),
+                line: i + 1,
+            };
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (users, permissions, namespaces, names) = match fields.as_slice() {
+                [users, permissions, namespaces] => (users, permissions, namespaces, &"*"),
+                [users, permissions, namespaces, names] => (users, permissions, namespaces, names),
+                _ => return Err(malformed()),
+            };
+            let users = match users.strip_prefix("key:") {
+                Some(key) => Users::Key(keys::encode(&keys::decode(key).ok_or_else(malformed)?)),
+                None => Users::Matching(users.as_bytes().to_vec()),
+            };
+            let permissions = permissions
+                .split(',')
+                .map(Permission::parse)
+                .collect::<Option<Vec<Permission>>>()
+                .ok_or_else(malformed)?;
+            rules.push(Rule {
+                users,
+                permissions,
+                namespaces: namespaces.as_bytes().to_vec(),
+                names: names.as_bytes().to_vec(),
+            });
+        }
+        Ok(Acl { rules })
+    }
+
+    /// Whether `user` may do what needs `permission` to the named structure in the namespace,
+    /// or, without a name, to the whole namespace.  Only rules covering every name in the
+    /// namespace grant anything on the namespace as a whole.
+    pub fn allows(&self, user: &str, permission: Permission, namespace: &str, name: Option<&str>) -> bool {
+        self.rules.iter().any(|rule| {
+            rule.permissions.contains(&permission)
+                && rule.users.covers(user)
+                && glob::matches(&rule.namespaces, namespace.as_bytes())
+                && match name {
+                    Some(name) => glob::matches(&rule.names, name.as_bytes()),
+                    None => rule.names == b"*",
+                }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A glob would take the `[` to start a class, and `?` to match any character.
+    const KEY: &str = "[ZC1ORp-YEBRP[Q3inc&jcw?nRmfg)aFp#PQhNuP";
+    // The same but for the character the `?` would match.
+    const OTHER_KEY: &str = "[ZC1ORp-YEBRP[Q3inc&jcwanRmfg)aFp#PQhNuP";
+
+    fn parse(contents: &str) -> Result<Acl, AclError> {
+        Acl::parse(Path::new("acl"), contents)
+    }
+
+    fn malformed_line(contents: &str) -> Option<usize> {
+        match parse(contents) {
+            Err(AclError::Malformed { line, .. }) => Some(line),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn skips_blank_lines_and_comments() {
+        let acl = parse("\n# alice read,write * \n   \nalice read team\n").ok().unwrap();
+        assert!(acl.allows("alice", Permission::Read, "team", Some("set")));
+        assert!(!acl.allows("alice", Permission::Write, "team", Some("set")));
+    }
+
+    #[test]
+    fn reports_malformed_lines() {
+        assert_eq!(malformed_line("alice read team\nbob\n"), Some(2));
+        assert_eq!(malformed_line("alice read,delete team"), Some(1));
+        assert_eq!(malformed_line("alice read team names extra"), Some(1));
+        assert_eq!(malformed_line("key:notakey read team"), Some(1));
+    }
+
+    #[test]
+    fn matches_users_namespaces_and_names_as_globs() {
+        let acl = parse("team-* read,write team-* cache:*").ok().unwrap();
+        assert!(acl.allows("team-a", Permission::Write, "team-b", Some("cache:1")));
+        assert!(!acl.allows("other", Permission::Write, "team-b", Some("cache:1")));
+        assert!(!acl.allows("team-a", Permission::Write, "other", Some("cache:1")));
+        assert!(!acl.allows("team-a", Permission::Write, "team-b", Some("other")));
+        assert!(!acl.allows("team-a", Permission::Admin, "team-b", Some("cache:1")));
+    }
+
+    #[test]
+    fn only_grants_whole_namespaces_to_rules_covering_every_name() {
+        let acl = parse("alice admin team cache:*\nbob admin team").ok().unwrap();
+        assert!(!acl.allows("alice", Permission::Admin, "team", None));
+        assert!(acl.allows("bob", Permission::Admin, "team", None));
+    }
+
+    #[test]
+    fn matches_keys_exactly() {
+        let acl = parse(&format!("key:{} read team", KEY)).ok().unwrap();
+        assert!(acl.allows(KEY, Permission::Read, "team", Some("set")));
+        assert!(!acl.allows(OTHER_KEY, Permission::Read, "team", Some("set")));
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::HashSet;
+
+use zmq;
+
+use crate::keys::{self, KeyPair, Tokens};
+
+/// Where libzmq looks for a ZAP handler to ask whether to let a connection in; see
+/// https://rfc.zeromq.org/spec/27/.
+pub const ZAP_ENDPOINT: &str = "inproc://zeromq.zap.01";
+
+const ZAP_VERSION: &[u8] = b"1.0";
+
+/// How clients prove they're allowed to connect.
+pub enum Auth {
+    /// Anyone who can reach the port gets in.
+    None,
+    /// Clients send a user name and token with PLAIN, and are known by the user name.  Tokens
+    /// cross the network in the clear, so this only keeps out clients that were never told one.
+    Tokens(Tokens),
+    /// Connections are encrypted with the server's keypair, and clients are known by their public
+    /// keys.  If `clients` is given, only clients with one of those keys get in; otherwise any
+    /// client that knows the server's public key does.
+    Curve {
+        keypair: KeyPair,
+        clients: Option<HashSet<Vec<u8>>>,
+    },
+}
+
+/// Whether a connection was let in, for the log.
+pub enum Decision {
+    Allowed,
+    Denied { mechanism: String, address: String },
+}
+
+impl Auth {
+    /// Sets up a server socket to use this before it's bound.
+    pub fn configure(&self, socket: &zmq::Socket) -> Result<(), zmq::Error> {
+        match self {
+            Auth::None => Ok(()),
+            Auth::Tokens(_) => socket.set_plain_server(true),
+            Auth::Curve { keypair, .. } => {
+                socket.set_curve_server(true)?;
+                socket.set_curve_secretkey(&keypair.secret)
+            }
+        }
+    }
+
+    /// Whether connections have to be checked by a ZAP handler.  Without one, libzmq lets in any
+    /// client that completes the handshake, without telling us who it is.
+    pub fn needs_handler(&self) -> bool {
+        match self {
+            Auth::None => false,
+            Auth::Tokens(_) | Auth::Curve { .. } => true,
+        }
+    }
+
+    /// Answers a ZAP request, returning the reply frames and the decision.  Messages from a
+    /// connection that was let in carry the client's user ID in their "User-Id" property.
+    pub fn handle(&self, request: Vec<Vec<u8>>) -> (Vec<Vec<u8>>, Decision) {
+        // Version, request ID, domain, address, identity and mechanism, then the credentials.
+        let mut frames = request.into_iter();
+        let mut next = || frames.next().unwrap_or_default();
+        let (version, request_id, _domain, address, _identity, mechanism) =
+            (next(), next(), next(), next(), next(), next());
+        let credentials: Vec<Vec<u8>> = frames.collect();
+
+        // The user ID of the client, if it's let in.
+        let user_id = match (self, mechanism.as_slice(), credentials.as_slice()) {
+            _ if version != ZAP_VERSION => None,
+            (Auth::Tokens(tokens), b"PLAIN", [user, password]) => {
+                let user = String::from_utf8_lossy(user).into_owned();
+                // Users with tokens of their own can't get in with the shared one, or anyone who
+                // knows it could pass themselves off as them.
+                let token = match tokens.users.get(&user) {
+                    Some(token) => Some(token),
+                    None => tokens.shared.as_ref(),
+                };
+                match token {
+                    Some(token) if constant_time_eq(password, token.as_bytes()) => Some(user),
+                    _ => None,
+                }
+            }
+            (Auth::Curve { clients, .. }, b"CURVE", [key]) => match clients {
+                Some(clients) if !clients.contains(key) => None,
+                _ => Some(keys::encode(key)),
+            },
+            _ => None,
+        };
+
+        let (status_code, status_text, decision): (&[u8], &[u8], _) = if user_id.is_some() {
+            (b"200", b"OK", Decision::Allowed)
+        } else {
+            let decision = Decision::Denied {
+                mechanism: String::from_utf8_lossy(&mechanism).into_owned(),
+                address: String::from_utf8_lossy(&address).into_owned(),
+            };
+            (b"400", b"Not allowed", decision)
+        };
+        let reply = vec![
+            ZAP_VERSION.to_vec(),
+            request_id,
+            status_code.to_vec(),
+            status_text.to_vec(),
+            user_id.unwrap_or_default().into_bytes(),
+            // No metadata.
+            vec![],
+        ];
+        (reply, decision)
+    }
+}
+
+// Compares every byte no matter where the first difference is, so how long a guess takes to be
+// turned down says nothing about how close it was.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
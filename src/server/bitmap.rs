@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+
+use actix::{Actor, Context, Handler, Message};
+use roaring::RoaringBitmap;
+
+pub struct BitmapAgent {
+    data: HashMap<String, RoaringBitmap>,
+}
+
+impl BitmapAgent {
+    pub fn new() -> BitmapAgent {
+        BitmapAgent {
+            data: HashMap::new(),
+        }
+    }
+}
+
+impl Actor for BitmapAgent {
+    type Context = Context<Self>;
+}
+
+pub enum BitmapError {
+    // The operation was given the wrong number of source bitmaps.
+    Arity { operation: Operation, sources: usize },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Operation {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+/// Sets or clears a single bit, resolving to the bit's previous value.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct SetBit {
+    pub name: String,
+    pub offset: u32,
+    pub value: bool,
+}
+
+impl Handler<SetBit> for BitmapAgent {
+    type Result = bool;
+
+    fn handle(
+        &mut self,
+        SetBit { name, offset, value }: SetBit,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        let bitmap = self.data.entry(name).or_default();
+        if value {
+            !bitmap.insert(offset)
+        } else {
+            bitmap.remove(offset)
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct GetBit {
+    pub name: String,
+    pub offset: u32,
+}
+
+impl Handler<GetBit> for BitmapAgent {
+    type Result = bool;
+
+    fn handle(&mut self, GetBit { name, offset }: GetBit, _ctx: &mut Context<Self>) -> Self::Result {
+        match self.data.get(&name) {
+            None => false,
+            Some(bitmap) => bitmap.contains(offset),
+        }
+    }
+}
+
+/// Resolves to the number of set bits.
+#[derive(Message)]
+#[rtype(result = "u64")]
+pub struct BitCount {
+    pub name: String,
+}
+
+impl Handler<BitCount> for BitmapAgent {
+    type Result = u64;
+
+    fn handle(&mut self, BitCount { name }: BitCount, _ctx: &mut Context<Self>) -> Self::Result {
+        match self.data.get(&name) {
+            None => 0,
+            Some(bitmap) => bitmap.len(),
+        }
+    }
+}
+
+/// Combines the source bitmaps and stores the result in the destination, resolving to the number
+/// of bits set in the result.  NOT takes exactly one source and flips its first `size` bits; a
+/// `size` of 0 means up to and including the highest bit set.  Missing sources count as empty.
+#[derive(Message)]
+#[rtype(result = "Result<u64, BitmapError>")]
+pub struct BitOp {
+    pub operation: Operation,
+    pub destination: String,
+    pub sources: Vec<String>,
+    pub size: u32,
+}
+
+impl Handler<BitOp> for BitmapAgent {
+    type Result = Result<u64, BitmapError>;
+
+    fn handle(
+        &mut self,
+        BitOp { operation, destination, sources, size }: BitOp,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        let empty = RoaringBitmap::new();
+        let mut bitmaps = sources
+            .iter()
+            .map(|name| self.data.get(name).unwrap_or(&empty));
+
+        let result = match (operation, sources.len()) {
+            (_, 0) | (Operation::Not, 2..=usize::MAX) => {
+                return Err(BitmapError::Arity {
+                    operation,
+                    sources: sources.len(),
+                })
+            }
+            (Operation::Not, _) => {
+                let source = bitmaps.next().unwrap();
+                let size = if size > 0 {
+                    size as u64
+                } else {
+                    source.max().map_or(0, |max| max as u64 + 1)
+                };
+                let mut all = RoaringBitmap::new();
+                if size > 0 {
+                    all.insert_range(0..=(size - 1) as u32);
+                }
+                all - source
+            }
+            (Operation::And, _) => {
+                let first = bitmaps.next().unwrap().clone();
+                bitmaps.fold(first, |acc, bitmap| acc & bitmap)
+            }
+            (Operation::Or, _) => bitmaps.fold(RoaringBitmap::new(), |acc, bitmap| acc | bitmap),
+            (Operation::Xor, _) => bitmaps.fold(RoaringBitmap::new(), |acc, bitmap| acc ^ bitmap),
+        };
+
+        let count = result.len();
+        self.data.insert(destination, result);
+        Ok(count)
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use actix::{Actor, Context, Handler, Message};
+
+// Used when a client adds to a filter that was never explicitly reserved.
+const DEFAULT_CAPACITY: u64 = 100_000;
+const DEFAULT_ERROR_RATE: f64 = 0.01;
+
+// The most bits one filter may take up: 1 GiB.
+const MAX_BITS: u64 = 1 << 33;
+
+// How many bits and hash functions a filter needs for the given capacity and false positive rate,
+// which `check` has to have accepted.
+fn size(capacity: u64, error_rate: f64) -> (u64, u32) {
+    let capacity = capacity as f64;
+    let ln2 = std::f64::consts::LN_2;
+
+    // The standard sizing formulas: m = -n ln(p) / ln(2)^2 and k = (m / n) ln(2).
+    let num_bits = ((-capacity * error_rate.ln()) / (ln2 * ln2)).ceil().max(64.0) as u64;
+    let num_hashes = ((num_bits as f64 / capacity) * ln2).round().max(1.0) as u32;
+    (num_bits, num_hashes)
+}
+
+/// A fixed-size Bloom filter.  Answers "have I seen this before?" with no false negatives and a
+/// false positive rate bounded by the one it was sized for, as long as no more than `capacity`
+/// distinct values are added.
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    pub fn new(capacity: u64, error_rate: f64) -> BloomFilter {
+        let (num_bits, num_hashes) = size(capacity, error_rate);
+        BloomFilter {
+            bits: vec![0; ((num_bits + 63) / 64) as usize],
+            num_bits,
+            num_hashes,
+        }
+    }
+
+    // Kirsch-Mitzenmacher double hashing; two independent hashes are enough to simulate k.
+    fn indexes(&self, value: &[u8]) -> impl Iterator<Item = u64> {
+        let mut first = DefaultHasher::new();
+        (0u8, value).hash(&mut first);
+        let first = first.finish();
+
+        let mut second = DefaultHasher::new();
+        (1u8, value).hash(&mut second);
+        let second = second.finish();
+
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64)
+            .map(move |i| first.wrapping_add(i.wrapping_mul(second)) % num_bits)
+    }
+
+    /// Adds the value, returning false if it was (probably) already present.
+    pub fn add(&mut self, value: &[u8]) -> bool {
+        let mut added = false;
+        for idx in self.indexes(value) {
+            let (word, mask) = ((idx / 64) as usize, 1 << (idx % 64));
+            if self.bits[word] & mask == 0 {
+                self.bits[word] |= mask;
+                added = true;
+            }
+        }
+        added
+    }
+
+    pub fn contains(&self, value: &[u8]) -> bool {
+        self.indexes(value)
+            .all(|idx| self.bits[(idx / 64) as usize] & (1 << (idx % 64)) != 0)
+    }
+}
+
+pub struct BloomAgent {
+    data: HashMap<String, BloomFilter>,
+}
+
+impl BloomAgent {
+    pub fn new() -> BloomAgent {
+        BloomAgent {
+            data: HashMap::new(),
+        }
+    }
+}
+
+impl Actor for BloomAgent {
+    type Context = Context<Self>;
+}
+
+pub enum BloomError {
+    // The capacity is 0, or the false positive rate isn't between 0 and 1.
+    Invalid { capacity: u64, error_rate: f64 },
+    // A filter this big would need more than MAX_BITS.
+    TooLarge { capacity: u64, error_rate: f64 },
+}
+
+// Whether a filter can be sized for the capacity and false positive rate.
+fn check(capacity: u64, error_rate: f64) -> Result<(), BloomError> {
+    // Written so that a NaN rate fails it too.
+    if capacity == 0 || !(error_rate > 0.0 && error_rate < 1.0) {
+        return Err(BloomError::Invalid { capacity, error_rate });
+    }
+    if size(capacity, error_rate).0 > MAX_BITS {
+        return Err(BloomError::TooLarge { capacity, error_rate });
+    }
+    Ok(())
+}
+
+/// Creates a filter sized for the given capacity and false positive rate.  Returns false if a
+/// filter by that name already exists, in which case it is left untouched.  The capacity has to
+/// be at least 1 and the rate strictly between 0 and 1.
+#[derive(Message)]
+#[rtype(result = "Result<bool, BloomError>")]
+pub struct Reserve {
+    pub name: String,
+    pub capacity: u64,
+    pub error_rate: f64,
+}
+
+impl Handler<Reserve> for BloomAgent {
+    type Result = Result<bool, BloomError>;
+
+    fn handle(
+        &mut self,
+        Reserve { name, capacity, error_rate }: Reserve,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        if self.data.contains_key(&name) {
+            return Ok(false);
+        }
+        check(capacity, error_rate)?;
+
+        self.data.insert(name, BloomFilter::new(capacity, error_rate));
+        Ok(true)
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct Add {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+impl Handler<Add> for BloomAgent {
+    type Result = bool;
+
+    fn handle(&mut self, Add { name, value }: Add, _ctx: &mut Context<Self>) -> Self::Result {
+        self.data
+            .entry(name)
+            .or_insert_with(|| BloomFilter::new(DEFAULT_CAPACITY, DEFAULT_ERROR_RATE))
+            .add(&value)
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct Exists {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+impl Handler<Exists> for BloomAgent {
+    type Result = bool;
+
+    fn handle(&mut self, Exists { name, value }: Exists, _ctx: &mut Context<Self>) -> Self::Result {
+        match self.data.get(&name) {
+            None => false,
+            Some(filter) => filter.contains(&value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_with_the_standard_formulas() {
+        assert_eq!(size(1000, 0.01), (9586, 7));
+        assert_eq!(size(100_000, 0.001), (1_437_759, 10));
+    }
+
+    #[test]
+    fn sizes_small_filters_to_at_least_one_word() {
+        assert_eq!(size(1, 0.5).0, 64);
+    }
+
+    #[test]
+    fn accepts_sensible_sizes() {
+        assert!(check(1, 0.5).is_ok());
+        assert!(check(DEFAULT_CAPACITY, DEFAULT_ERROR_RATE).is_ok());
+    }
+
+    #[test]
+    fn rejects_an_error_rate_outside_zero_to_one() {
+        for error_rate in &[0.0, -0.1, 1.0, 1.5, f64::NAN, f64::INFINITY] {
+            assert!(matches!(check(1000, *error_rate), Err(BloomError::Invalid { .. })));
+        }
+    }
+
+    #[test]
+    fn rejects_a_zero_capacity() {
+        assert!(matches!(check(0, 0.01), Err(BloomError::Invalid { .. })));
+    }
+
+    #[test]
+    fn rejects_filters_past_the_most_bits() {
+        assert!(matches!(check(u64::MAX, 0.01), Err(BloomError::TooLarge { .. })));
+    }
+
+    #[test]
+    fn never_forgets_a_value() {
+        let mut filter = BloomFilter::new(10_000, 0.01);
+        for i in 0..10_000u32 {
+            filter.add(&i.to_be_bytes());
+        }
+        for i in 0..10_000u32 {
+            assert!(filter.contains(&i.to_be_bytes()));
+        }
+    }
+
+    #[test]
+    fn keeps_false_positives_near_the_error_rate() {
+        let mut filter = BloomFilter::new(10_000, 0.01);
+        for i in 0..10_000u32 {
+            filter.add(&i.to_be_bytes());
+        }
+        let false_positives = (10_000..20_000u32).filter(|i| filter.contains(&i.to_be_bytes())).count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn says_whether_a_value_was_new() {
+        let mut filter = BloomFilter::new(100, 0.01);
+        assert!(filter.add(b"a"));
+        assert!(!filter.add(b"a"));
+        assert!(!filter.contains(b"b"));
+    }
+}
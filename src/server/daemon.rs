@@ -0,0 +1,183 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+#[cfg(target_os = "linux")]
+use std::mem;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use libc;
+
+// Turns the -1 libc calls fail with into the error they left in errno.
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+fn open_output(log_file: Option<&Path>) -> io::Result<File> {
+    match log_file {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path),
+        None => OpenOptions::new().write(true).open("/dev/null"),
+    }
+}
+
+/// Sends everything printed to stdout and stderr to the end of the file instead.
+pub fn redirect_output(log_file: &Path) -> io::Result<()> {
+    let output = open_output(Some(log_file))?;
+    check(unsafe { libc::dup2(output.as_raw_fd(), libc::STDOUT_FILENO) })?;
+    check(unsafe { libc::dup2(output.as_raw_fd(), libc::STDERR_FILENO) })?;
+    Ok(())
+}
+
+/// The detached server's end of the pipe its parent waits on.
+pub struct Detached(File);
+
+impl Detached {
+    /// Lets the parent exit successfully, now that the server is taking requests.
+    pub fn ready(mut self) {
+        let _ = self.0.write_all(&[1]);
+    }
+}
+
+/// Carries on in a child process in a session of its own, away from the terminal, printing to
+/// the log file if given and nowhere otherwise.  The parent waits for the child to say it's ready
+/// and exits successfully, or exits with the child's status if the child exits first, so whatever
+/// started the server knows it's up once the parent is gone.
+///
+/// Has to be called before any threads are started, since only the calling thread carries on in
+/// the child.
+pub fn detach(log_file: Option<&Path>) -> io::Result<Detached> {
+    // Opened up front, so a bad path is reported on the terminal.
+    let output = open_output(log_file)?;
+    let input = File::open("/dev/null")?;
+    let mut fds = [0; 2];
+    check(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+    let (mut waiting, ready) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    let child = check(unsafe { libc::fork() })?;
+    if child != 0 {
+        drop(ready);
+        let mut byte = [0];
+        if let Ok(1) = waiting.read(&mut byte) {
+            process::exit(0);
+        }
+        let mut status = 0;
+        if unsafe { libc::waitpid(child, &mut status, 0) == child && libc::WIFEXITED(status) } {
+            process::exit(unsafe { libc::WEXITSTATUS(status) });
+        }
+        process::exit(1);
+    }
+
+    drop(waiting);
+    check(unsafe { libc::setsid() })?;
+    check(unsafe { libc::dup2(input.as_raw_fd(), libc::STDIN_FILENO) })?;
+    check(unsafe { libc::dup2(output.as_raw_fd(), libc::STDOUT_FILENO) })?;
+    check(unsafe { libc::dup2(output.as_raw_fd(), libc::STDERR_FILENO) })?;
+    Ok(Detached(ready))
+}
+
+/// Holds the server's process ID while it runs, locked so no other server can take it over, and
+/// removed when dropped.
+pub struct Pidfile {
+    path: PathBuf,
+    // Holds the lock until the file is removed.
+    _file: File,
+}
+
+impl Pidfile {
+    /// Fails without touching the file if another server holds it.
+    pub fn create(path: &Path) -> io::Result<Pidfile> {
+        let mut file = loop {
+            // Left as it is until it's locked, in case it's another server's.
+            let file = OpenOptions::new().write(true).create(true).truncate(false).open(path)?;
+            if let Err(error) = check(unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) }) {
+                return Err(match error.kind() {
+                    io::ErrorKind::WouldBlock => {
+                        io::Error::new(io::ErrorKind::AlreadyExists, "another server is running with it")
+                    }
+                    _ => error,
+                });
+            }
+            // The server that held it may have removed it between it being opened and locked, in
+            // which case the lock is on a file nobody else will look at.
+            let (opened, current) = (file.metadata()?, fs::metadata(path));
+            match current {
+                Ok(current) if (current.dev(), current.ino()) == (opened.dev(), opened.ino()) => break file,
+                Ok(_) => continue,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(error),
+            }
+        };
+        file.set_len(0)?;
+        writeln!(file, "{}", process::id())?;
+        Ok(Pidfile {
+            path: path.to_owned(),
+            _file: file,
+        })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Tells systemd how the server is getting on, e.g. "READY=1", if it started the server with
+/// Type=notify, by sending the state to the socket it names in NOTIFY_SOCKET.  Does nothing
+/// otherwise.
+pub fn notify(state: &str) -> io::Result<()> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let socket = UnixDatagram::unbound()?;
+    // A leading @ stands for the NUL that starts a name in the abstract namespace, which only
+    // Linux has.
+    #[cfg(target_os = "linux")]
+    {
+        if let Some((b'@', name)) = path.as_bytes().split_first() {
+            return send_to_abstract(&socket, name, state.as_bytes());
+        }
+    }
+    socket.send_to(state.as_bytes(), &path)?;
+    Ok(())
+}
+
+// Sends to the socket with the name in the abstract namespace, which std can't address on the
+// compilers this builds with.
+#[cfg(target_os = "linux")]
+fn send_to_abstract(socket: &UnixDatagram, name: &[u8], data: &[u8]) -> io::Result<()> {
+    let mut address: libc::sockaddr_un = unsafe { mem::zeroed() };
+    address.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    // After the NUL the path starts with.
+    if name.len() >= address.sun_path.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the socket name is too long"));
+    }
+    for (slot, byte) in address.sun_path[1..].iter_mut().zip(name) {
+        *slot = *byte as libc::c_char;
+    }
+    let length = mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+    let sent = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            data.as_ptr() as *const libc::c_void,
+            data.len(),
+            0,
+            &address as *const libc::sockaddr_un as *const libc::sockaddr,
+            length as libc::socklen_t,
+        )
+    };
+    if sent == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -1,9 +1,14 @@
+use std::io;
+use std::time::Duration;
+
 use actix::{Actor, Context, Handler, Message};
 use log::error;
 use prost;
 use simple_logger::SimpleLogger;
 use zmq;
 
+use crate::server::acl::Permission;
+
 pub struct ErrorServer {
     engine: SimpleLogger,
 }
@@ -112,6 +117,76 @@ impl Handler<SocketRecvError> for ErrorServer {
     }
 }
 
+// The messenger's socket failed and is being opened again after `delay`, or won't be and the
+// server is exiting without one.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct MessengerRestart {
+    pub restarts: u32,
+    pub delay: Option<Duration>,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Handler<MessengerRestart> for ErrorServer {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        MessengerRestart { restarts, delay, host, port }: MessengerRestart,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        match delay {
+            Some(delay) => error!(
+                "Reopening the ZeroMQ socket on tcp://{}:{} in {:?}, after {} failure(s) in a row",
+                host, port, delay, restarts
+            ),
+            None => error!(
+                "Giving up on the ZeroMQ socket on tcp://{}:{} after {} failure(s) in a row; exiting",
+                host, port, restarts
+            ),
+        }
+    }
+}
+
+// The server was asked to shut down, but requests were still in flight once it had waited as
+// long as it would.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DrainTimeoutError(pub Duration);
+
+impl Handler<DrainTimeoutError> for ErrorServer {
+    type Result = ();
+
+    fn handle(&mut self, DrainTimeoutError(timeout): DrainTimeoutError, _ctx: &mut Context<Self>) -> Self::Result {
+        error!("Shutting down with requests left unanswered after waiting {:?} for them", timeout)
+    }
+}
+
+// Something went wrong with the descriptor the server sleeps on until the socket has data.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SocketWaitError {
+    pub error: io::Error,
+    pub host: String,
+    pub port: u16,
+}
+
+impl Handler<SocketWaitError> for ErrorServer {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        SocketWaitError { error, host, port }: SocketWaitError,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        error!(
+            "Could not wait for data on the ZeroMQ socket on tcp://{}:{}; got error: {}",
+            host, port, error
+        )
+    }
+}
+
 // Represents an inability to send a response to the given client, probably causing a timeout on
 // their end.
 #[derive(Message)]
@@ -136,3 +211,114 @@ impl Handler<UnsentResponseError> for ErrorServer {
         )
     }
 }
+
+// A data structure agent went away before answering a request, most likely because the system is
+// shutting down.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AgentMailboxError(pub actix::MailboxError);
+
+impl Handler<AgentMailboxError> for ErrorServer {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        AgentMailboxError(error): AgentMailboxError,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        error!("Could not get a response from a data structure agent; got error: {}", error)
+    }
+}
+
+// A client asked for a protocol version this server doesn't speak.  Version 0 means the client
+// predates the handshake altogether.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct UnsupportedVersionError {
+    pub client_name: String,
+    pub protocol_version: u32,
+}
+
+impl Handler<UnsupportedVersionError> for ErrorServer {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        UnsupportedVersionError { client_name, protocol_version }: UnsupportedVersionError,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        error!(
+            "Turned away client {:?} speaking unsupported protocol version {}",
+            client_name, protocol_version
+        )
+    }
+}
+
+// The socket couldn't be set up to authenticate clients; it's left closed rather than opened to
+// everyone.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SocketSecurityError(pub zmq::Error);
+
+impl Handler<SocketSecurityError> for ErrorServer {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        SocketSecurityError(error): SocketSecurityError,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        error!("Could not set up authentication on the ZeroMQ socket; got error: {}", error)
+    }
+}
+
+// A client was turned away for not presenting an allowed token or key.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AuthenticationError {
+    pub mechanism: String,
+    pub address: String,
+}
+
+impl Handler<AuthenticationError> for ErrorServer {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        AuthenticationError { mechanism, address }: AuthenticationError,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        error!("Turned away a client at {} authenticating with {}", address, mechanism)
+    }
+}
+
+// The ACL turned a request down.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PermissionDeniedError {
+    pub user: String,
+    pub permission: Permission,
+    pub namespace: String,
+    pub name: Option<String>,
+}
+
+impl Handler<PermissionDeniedError> for ErrorServer {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        PermissionDeniedError { user, permission, namespace, name }: PermissionDeniedError,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        match name {
+            Some(name) => error!(
+                "Denied user {:?} {} permission on {:?} in namespace {:?}",
+                user, permission, name, namespace
+            ),
+            None => error!(
+                "Denied user {:?} {} permission on namespace {:?}",
+                user, permission, namespace
+            ),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+/// Matches a value against a glob pattern, byte by byte.  `*` matches any run of bytes, `?` any
+/// single byte, `[abc]`, `[a-z]` and `[^a-z]` (or `[!a-z]`) a byte in or out of a class, and `\`
+/// makes the byte after it match only itself.
+pub fn matches(pattern: &[u8], value: &[u8]) -> bool {
+    let (mut p, mut v) = (0, 0);
+    // Where the last `*` was, and where in the value it started matching; if what follows the star
+    // stops matching, the star takes one more byte and matching picks up from there.
+    let mut star = None;
+
+    while v < value.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, v));
+                p += 1;
+                continue;
+            }
+            Some(_) => {
+                if let Some(len) = match_one(&pattern[p..], value[v]) {
+                    p += len;
+                    v += 1;
+                    continue;
+                }
+            }
+            None => (),
+        }
+
+        match star {
+            Some((star_p, star_v)) => {
+                star = Some((star_p, star_v + 1));
+                p = star_p + 1;
+                v = star_v + 1;
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// Matches a byte against the element at the start of the pattern, resolving to the element's
+// length if it matches.
+fn match_one(pattern: &[u8], byte: u8) -> Option<usize> {
+    match pattern[0] {
+        b'?' => Some(1),
+        b'\\' if pattern.len() > 1 => {
+            if pattern[1] == byte {
+                Some(2)
+            } else {
+                None
+            }
+        }
+        b'[' => {
+            let negate = matches!(pattern.get(1), Some(b'^') | Some(b'!'));
+            let mut i = if negate { 2 } else { 1 };
+            let mut matched = false;
+            // A `]` right at the start is part of the class rather than the end of it.
+            let mut first = true;
+            loop {
+                match pattern.get(i) {
+                    // No closing bracket, so the `[` was meant literally.
+                    None => return if byte == b'[' { Some(1) } else { None },
+                    Some(b']') if !first => break,
+                    Some(&start) => {
+                        match (pattern.get(i + 1), pattern.get(i + 2)) {
+                            (Some(b'-'), Some(&end)) if end != b']' => {
+                                matched |= start <= byte && byte <= end;
+                                i += 3;
+                            }
+                            _ => {
+                                matched |= start == byte;
+                                i += 1;
+                            }
+                        }
+                    }
+                }
+                first = false;
+            }
+            if matched != negate {
+                Some(i + 1)
+            } else {
+                None
+            }
+        }
+        c => {
+            if c == byte {
+                Some(1)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str, value: &str) -> bool {
+        matches(pattern.as_bytes(), value.as_bytes())
+    }
+
+    #[test]
+    fn matches_literals_exactly() {
+        assert!(glob("cache", "cache"));
+        assert!(!glob("cache", "caches"));
+        assert!(!glob("cache", "cach"));
+    }
+
+    #[test]
+    fn matches_a_star_at_the_start() {
+        assert!(glob("*:1", "cache:1"));
+        assert!(glob("*:1", ":1"));
+        assert!(!glob("*:1", "cache:2"));
+    }
+
+    #[test]
+    fn matches_a_star_in_the_middle() {
+        assert!(glob("cache:*:hits", "cache:a:b:hits"));
+        assert!(glob("cache:*:hits", "cache::hits"));
+        assert!(!glob("cache:*:hits", "cache:a:misses"));
+    }
+
+    #[test]
+    fn matches_a_star_at_the_end() {
+        assert!(glob("cache:*", "cache:"));
+        assert!(glob("cache:*", "cache:anything"));
+        assert!(!glob("cache:*", "cach"));
+    }
+
+    #[test]
+    fn backtracks_across_several_stars() {
+        assert!(glob("*a*b*", "xxaxxbxx"));
+        assert!(glob("a*a*a", "aaa"));
+        assert!(!glob("*a*b*", "xxbxxaxx"));
+        assert!(glob("**", ""));
+    }
+
+    #[test]
+    fn matches_a_single_byte_with_a_question_mark() {
+        assert!(glob("cache:?", "cache:1"));
+        assert!(!glob("cache:?", "cache:"));
+        assert!(!glob("cache:?", "cache:12"));
+    }
+
+    #[test]
+    fn matches_escaped_bytes_only_literally() {
+        assert!(glob(r"cache\*", "cache*"));
+        assert!(!glob(r"cache\*", "caches"));
+        assert!(glob(r"what\?", "what?"));
+        assert!(!glob(r"what\?", "whats"));
+        assert!(glob(r"back\\slash", r"back\slash"));
+    }
+
+    #[test]
+    fn matches_a_trailing_backslash_literally() {
+        assert!(glob("cache\\", "cache\\"));
+    }
+
+    #[test]
+    fn matches_bytes_in_a_class() {
+        assert!(glob("team-[abc]", "team-b"));
+        assert!(!glob("team-[abc]", "team-d"));
+        assert!(glob("team-[a-c]", "team-c"));
+        assert!(!glob("team-[a-c]", "team-d"));
+        assert!(glob("team-[0-9a-f]", "team-e"));
+    }
+
+    #[test]
+    fn matches_bytes_out_of_a_negated_class() {
+        assert!(glob("team-[^a-c]", "team-d"));
+        assert!(!glob("team-[^a-c]", "team-a"));
+        assert!(glob("team-[!a-c]", "team-d"));
+        assert!(!glob("team-[!a-c]", "team-b"));
+    }
+
+    #[test]
+    fn takes_a_leading_bracket_or_trailing_dash_as_part_of_a_class() {
+        assert!(glob("[]a]", "]"));
+        assert!(glob("[a-]", "-"));
+        assert!(!glob("[^]]", "]"));
+    }
+
+    #[test]
+    fn matches_an_unclosed_bracket_literally() {
+        assert!(glob("team[a", "team[a"));
+        assert!(!glob("team[a", "teama"));
+    }
+
+    #[test]
+    fn matches_only_empty_values_with_an_empty_pattern() {
+        assert!(glob("", ""));
+        assert!(!glob("", "a"));
+        assert!(glob("*", ""));
+        assert!(!glob("?", ""));
+    }
+
+    #[test]
+    fn matches_non_ascii_names_byte_by_byte() {
+        assert!(glob("café", "café"));
+        assert!(glob("caf*", "café"));
+        assert!(glob("*é", "café"));
+        // é takes two bytes in UTF-8.
+        assert!(!glob("caf?", "café"));
+        assert!(glob("caf??", "café"));
+        assert!(glob("名前:*", "名前:一"));
+    }
+}
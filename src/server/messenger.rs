@@ -1,122 +1,1325 @@
-use std::io::Cursor;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, Cursor};
+use std::pin::Pin;
+use std::task::{self, Poll};
+use std::time::{Duration, Instant};
 
-use actix::prelude::SendError;
-use actix::{Actor, ActorContext, Addr, AsyncContext, Context, Handler};
+use actix::{
+    Actor, ActorContext, ActorFuture, Addr, AsyncContext, Context, Handler, MailboxError,
+    Supervised, WrapFuture,
+};
 use prost::Message;
+use tokio::sync::oneshot;
+use tokio::time::{delay_for, timeout};
 use zmq;
 
 use crate::client::messages as cm;
-use crate::server::messages as m;
+use crate::server::acl::{Acl, Permission};
+use crate::server::auth::{Auth, Decision, ZAP_ENDPOINT};
+use crate::server::bitmap::{self, BitmapAgent, BitmapError};
+use crate::server::bloom::{self, BloomAgent, BloomError};
 use crate::server::errors::{
-    ErrorServer, MessageDecodeError, SocketConnectionError, SocketOpenError, SocketRecvError, UnsentResponseError,
+    AgentMailboxError, AuthenticationError, ErrorServer, MessageDecodeError, MessengerRestart,
+    PermissionDeniedError, SocketConnectionError, SocketOpenError, SocketRecvError,
+    SocketSecurityError, SocketWaitError, UnsentResponseError, UnsupportedVersionError,
 };
-use crate::server::set::{self, SetAgent};
+use crate::server::messages as m;
+use crate::server::ratelimit::{self, Acquired, RateLimitAgent, RateLimitError};
+use crate::server::readiness::Readiness;
+use crate::server::set::{self, SetError, SetShards};
+use crate::server::sketch::{self, SketchAgent};
+use crate::server::stream::{self, ReadResult, StreamAgent, StreamError};
+
+// The routing frames a ROUTER socket prepends to every message it receives; replies must be sent
+// with the same frames in front so they make it back to the right client.
+type Envelope = Vec<Vec<u8>>;
+
+// The range of protocol versions this server speaks.  Clients from before the handshake existed
+// never say Hello, and count as version 0.
+const MIN_PROTOCOL_VERSION: u32 = 1;
+const PROTOCOL_VERSION: u32 = 1;
+
+// What a client may ask for in its Hello.
+const CAPABILITIES: &[&str] = &[
+    "set", "multiset", "bloom", "sketch", "topk", "bitmap", "ratelimit", "stream",
+];
+
+// How many members a scan looks at when the client doesn't say.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+// Scan cursors are a marker byte followed by the member to continue after, so that an empty
+// cursor always means a fresh scan, even in a set holding an empty member.  Clients should treat
+// them as opaque.
+const SCAN_CURSOR_MARKER: u8 = 1;
+
+// The most members a random pick allowing duplicates returns, since it returns as many as it's
+// asked for no matter how small the set is.
+const MAX_RAND_MEMBERS: u32 = 100_000;
+
+// The most messages read in one go before the agents' answers get a chance to go out.
+const RECV_BATCH: usize = 64;
+
+// How long to wait before reopening the socket after the first failure in a row, doubling with
+// each one after up to the most, and how many in a row to put up with before giving up.
+const FIRST_RESTART_DELAY: Duration = Duration::from_millis(100);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+const MAX_RESTARTS: u32 = 10;
+
+// How long a session can go unused before it's forgotten, since clients that go away never say
+// so, and how often to look for those.  A client coming back after that is told to say Hello again.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// EX_UNAVAILABLE from sysexits.h, exited with once the socket can't be brought back.
+const EXIT_UNAVAILABLE: i32 = 69;
+
+// Everything needed to send a reply back to the request it answers.
+struct Origin {
+    envelope: Envelope,
+    session_id: u32,
+    sequence: u64,
+    // The namespace set and multiset requests go to.
+    namespace: String,
+    // Who the client authenticated as; empty if it didn't.
+    user: String,
+}
+
+struct Session {
+    // The client's ROUTER identity, so one client can't pass itself off as another.
+    identity: Vec<u8>,
+    namespace: String,
+    // When the client last sent a request under it.
+    last_seen: Instant,
+}
+
+// The request ID is filled in by `respond`, which knows which request is being answered.
+fn error(code: cm::ErrorCode, message: String) -> cm::wire_message::Inner {
+    cm::wire_message::Inner::Error(cm::Error {
+        code: code as i32,
+        message,
+        request_id: 0,
+    })
+}
+
+fn set_error(set_error: SetError) -> cm::wire_message::Inner {
+    match set_error {
+        SetError::WrongType { name } => error(
+            cm::ErrorCode::WrongType,
+            format!("{} holds a different kind of structure", name),
+        ),
+        SetError::Conflict { name } => error(
+            cm::ErrorCode::Conflict,
+            format!("{} changed since it was watched", name),
+        ),
+        SetError::QuotaExceeded { quota } => error(
+            cm::ErrorCode::CapacityExceeded,
+            format!("The namespace is limited to {} members", quota),
+        ),
+        SetError::Full { name, max_members } => error(
+            cm::ErrorCode::CapacityExceeded,
+            format!("{} is limited to {} members", name, max_members),
+        ),
+        SetError::OutOfMemory { max_memory } => error(
+            cm::ErrorCode::OutOfMemory,
+            format!("The server is limited to {} bytes of sets per shard", max_memory),
+        ),
+    }
+}
+
+// What a request needs permission to do to one structure, or to the whole namespace without a
+// name.
+struct Access<'a> {
+    permission: Permission,
+    // Structures other than sets and multisets aren't namespaced yet, so they count as being in
+    // the default namespace.
+    namespaced: bool,
+    name: Option<&'a str>,
+}
+
+impl<'a> Access<'a> {
+    fn set(permission: Permission, name: &'a str) -> Self {
+        Access {
+            permission,
+            namespaced: true,
+            name: Some(name),
+        }
+    }
+
+    fn other(permission: Permission, name: &'a str) -> Self {
+        Access {
+            permission,
+            namespaced: false,
+            name: Some(name),
+        }
+    }
+
+    fn namespace(permission: Permission) -> Self {
+        Access {
+            permission,
+            namespaced: true,
+            name: None,
+        }
+    }
+}
+
+fn access(inner: &m::wire_message::Inner) -> Vec<Access<'_>> {
+    use m::wire_message::Inner as Request;
+    use Permission::{Admin, Read, Write};
+
+    let set = Access::set;
+    let other = Access::other;
+    let namespace = Access::namespace;
+
+    match inner {
+        Request::Hello(_) => vec![],
+        Request::SetInsert(m::SetInsert { name, .. })
+        | Request::SetInsertMany(m::SetInsertMany { name, .. })
+        | Request::SetPop(m::SetPop { name, .. })
+        | Request::SetExpire(m::SetExpire { name, .. })
+        | Request::SetCreate(m::SetCreate { name, .. })
+        | Request::MultisetInsert(m::MultisetInsert { name, .. }) => vec![set(Write, name)],
+        Request::SetScan(m::SetScan { name, .. })
+        | Request::SetRandMember(m::SetRandMember { name, .. })
+        | Request::MultisetMembers(m::MultisetMembers { name }) => vec![set(Read, name)],
+        Request::SetMove(m::SetMove {
+            source, destination, ..
+        }) => vec![set(Write, source), set(Write, destination)],
+        Request::InsertUnless(m::InsertUnless { name, unless_in, .. }) => {
+            let mut access = vec![set(Write, name)];
+            access.extend(unless_in.iter().map(|other| set(Read, other)));
+            access
+        }
+        Request::Multi(m::Multi { ops, watches }) => {
+            let mut access: Vec<Access> = ops
+                .iter()
+                .filter_map(|m::Op { op }| match op {
+                    Some(m::op::Op::Insert(m::SetInsert { name, .. }))
+                    | Some(m::op::Op::Remove(m::SetRemove { name, .. }))
+                    | Some(m::op::Op::Increment(m::MultisetInsert { name, .. })) => Some(set(Write, name)),
+                    Some(m::op::Op::Version(m::SetVersion { name })) => Some(set(Read, name)),
+                    None => None,
+                })
+                .collect();
+            access.extend(watches.iter().map(|m::Watch { name, .. }| set(Read, name)));
+            access
+        }
+        Request::ListNames(_) => vec![namespace(Read)],
+        Request::FlushNamespace(_) | Request::SetQuota(_) => vec![namespace(Admin)],
+        Request::SetBit(m::SetBit { name, .. })
+        | Request::Acquire(m::Acquire { name, .. })
+        | Request::StreamAppend(m::StreamAppend { name, .. })
+        | Request::StreamCommit(m::StreamCommit { name, .. })
+        | Request::BfReserve(m::BfReserve { name, .. })
+        | Request::BfAdd(m::BfAdd { name, .. })
+        | Request::CmsIncr(m::CmsIncr { name, .. })
+        | Request::TopKAdd(m::TopKAdd { name, .. }) => vec![other(Write, name)],
+        Request::GetBit(m::GetBit { name, .. })
+        | Request::BitCount(m::BitCount { name })
+        | Request::StreamRead(m::StreamRead { name, .. })
+        | Request::BfExists(m::BfExists { name, .. })
+        | Request::CmsQuery(m::CmsQuery { name, .. })
+        | Request::TopKList(m::TopKList { name }) => vec![other(Read, name)],
+        Request::BitOp(m::BitOp {
+            destination, sources, ..
+        }) => {
+            let mut access = vec![other(Write, destination)];
+            access.extend(sources.iter().map(|source| other(Read, source)));
+            access
+        }
+    }
+}
+
+fn bitmap_error(bitmap_error: BitmapError) -> cm::wire_message::Inner {
+    match bitmap_error {
+        BitmapError::Arity { operation, sources } => error(
+            cm::ErrorCode::InvalidArgument,
+            format!("{:?} cannot be applied to {} bitmaps", operation, sources),
+        ),
+    }
+}
+
+fn bloom_error(bloom_error: BloomError) -> cm::wire_message::Inner {
+    match bloom_error {
+        BloomError::Invalid { capacity, error_rate } => error(
+            cm::ErrorCode::InvalidArgument,
+            format!(
+                "A Bloom filter needs a capacity of at least 1 and a false positive rate between 0 and 1, not {} and {}",
+                capacity, error_rate
+            ),
+        ),
+        BloomError::TooLarge { capacity, error_rate } => error(
+            cm::ErrorCode::CapacityExceeded,
+            format!(
+                "A Bloom filter for {} values with a false positive rate of {} would be too large",
+                capacity, error_rate
+            ),
+        ),
+    }
+}
+
+fn rate_limit_error(rate_limit_error: RateLimitError) -> cm::wire_message::Inner {
+    match rate_limit_error {
+        RateLimitError::Invalid => error(
+            cm::ErrorCode::InvalidArgument,
+            "The rate has to be positive, and the time the cost or burst takes to refill small enough to count"
+                .to_owned(),
+        ),
+        RateLimitError::Backlogged { name } => error(
+            cm::ErrorCode::CapacityExceeded,
+            format!("Reservations on rate limiter {} reach too far into the future", name),
+        ),
+    }
+}
+
+fn stream_error(stream_error: StreamError) -> cm::wire_message::Inner {
+    match stream_error {
+        StreamError::NotFound { name } => {
+            error(cm::ErrorCode::NotFound, format!("There is no stream named {}", name))
+        }
+    }
+}
+
+/// The data structure agents requests are dispatched to.
+#[derive(Clone)]
+pub struct Agents {
+    pub set: SetShards,
+    pub bloom: Addr<BloomAgent>,
+    pub sketch: Addr<SketchAgent>,
+    pub bitmap: Addr<BitmapAgent>,
+    pub rate_limit: Addr<RateLimitAgent>,
+    pub stream: Addr<StreamAgent>,
+}
 
 pub struct MessengerServer {
     ctx: zmq::Context,
     host: String,
     port: u16,
     error_server_addr: Addr<ErrorServer>,
-    set_agent_addr: Addr<SetAgent>,
+    agents: Agents,
+    auth: Auth,
+    // Who may do what; everyone may do anything without one.
+    acl: Option<Acl>,
+    // What the read loop sleeps on until `socket` and `zap` have something to read, declared
+    // first so they're dropped before the sockets are closed.
+    readiness: Option<Readiness>,
+    zap_readiness: Option<Readiness>,
     socket: Option<zmq::Socket>,
+    // Answers libzmq's questions about whether to let each new connection in, if `auth` needs it.
+    zap: Option<zmq::Socket>,
+    sessions: HashMap<u32, Session>,
+    next_session_id: u32,
+    // Times the socket has failed since a message last came in on it.
+    restarts: u32,
+    // Requests read but not answered yet.
+    in_flight: usize,
+    // Set once the server is shutting down, after which nothing more is read.
+    draining: bool,
+    // Told once the last request in flight has been answered while draining.
+    drained: Option<oneshot::Sender<()>>,
+    // Told once the socket is first open for requests, so only there until then.
+    ready: Option<oneshot::Sender<()>>,
+    // Told the status to exit with once the socket is given up on.
+    failed: Option<oneshot::Sender<i32>>,
+}
+
+/// Who to tell how the messenger is getting on.
+pub struct Lifecycle {
+    /// Told once the socket is first open for requests.
+    pub ready: oneshot::Sender<()>,
+    /// Told the status to exit with if the socket can't be brought back, for the server to clean
+    /// up and exit.
+    pub failed: oneshot::Sender<i32>,
 }
 
 impl MessengerServer {
     pub fn new(
         host: &str,
         port: u16,
+        auth: Auth,
+        acl: Option<Acl>,
         error_server_addr: Addr<ErrorServer>,
-        set_agent_addr: Addr<SetAgent>,
+        agents: Agents,
+        Lifecycle { ready, failed }: Lifecycle,
     ) -> Self {
         MessengerServer {
             ctx: zmq::Context::new(),
             host: host.to_owned(),
             port,
             error_server_addr,
-            set_agent_addr,
+            agents,
+            auth,
+            acl,
+            readiness: None,
+            zap_readiness: None,
             socket: None,
+            zap: None,
+            sessions: HashMap::new(),
+            next_session_id: 1,
+            restarts: 0,
+            in_flight: 0,
+            draining: false,
+            drained: None,
+            ready: Some(ready),
+            failed: Some(failed),
         }
     }
-}
 
-impl Actor for MessengerServer {
-    type Context = Context<Self>;
+    fn respond(&self, origin: Origin, mut inner: cm::wire_message::Inner) {
+        let Origin {
+            envelope,
+            session_id,
+            sequence,
+            ..
+        } = origin;
+        if let cm::wire_message::Inner::Error(error) = &mut inner {
+            error.request_id = sequence;
+        }
+        let message = cm::WireMessage {
+            id: session_id,
+            sequence,
+            inner: Some(inner),
+        };
+        let mut buf = vec![];
+        buf.reserve(message.encoded_len());
+        message.encode(&mut buf).unwrap();
+
+        let mut frames = envelope;
+        frames.push(buf);
+
+        let sent = match &self.socket {
+            Some(socket) => socket.send_multipart(frames, 0).is_ok(),
+            None => false,
+        };
+        if let Some(readiness) = &self.readiness {
+            readiness.sent();
+        }
+
+        // Log that a client would not have received a response to their request.  Not much more
+        // we can do there.  Clients should have a timeout due to the possibility of encountering
+        // an error like this.
+        if !sent {
+            self.error_server_addr.do_send(UnsentResponseError {
+                client_id: session_id,
+                host: self.host.clone(),
+                port: self.port,
+            })
+        }
+    }
+
+    // Waits on a request to one of the data structure agents without blocking the read loop, and
+    // sends the client whatever the agent answered with.
+    fn reply_with<F, T, R>(
+        &mut self,
+        ctx: &mut Context<Self>,
+        request: F,
+        origin: Origin,
+        reply: R,
+    ) where
+        F: Future<Output = Result<T, MailboxError>> + 'static,
+        R: FnOnce(T) -> cm::wire_message::Inner + 'static,
+    {
+        self.in_flight += 1;
+        ctx.spawn(
+            request
+                .into_actor(self)
+                .map(move |result, act, _ctx| {
+                    match result {
+                        Ok(result) => act.respond(origin, reply(result)),
+                        Err(mailbox_error) => {
+                            act.respond(
+                                origin,
+                                error(cm::ErrorCode::Internal, mailbox_error.to_string()),
+                            );
+                            act.error_server_addr.do_send(AgentMailboxError(mailbox_error));
+                        }
+                    }
+                    act.in_flight -= 1;
+                    act.check_drained();
+                }),
+        );
+    }
+
+    fn check_drained(&mut self) {
+        if self.in_flight == 0 {
+            if let Some(drained) = self.drained.take() {
+                let _ = drained.send(());
+            }
+        }
+    }
+
+    // Answers every connection waiting to be let in.
+    fn authenticate(&self) {
+        let zap = match &self.zap {
+            Some(zap) => zap,
+            None => return,
+        };
+        while let Ok(request) = zap.recv_multipart(zmq::DONTWAIT) {
+            let (reply, decision) = self.auth.handle(request);
+            if let Decision::Denied { mechanism, address } = decision {
+                self.error_server_addr
+                    .do_send(AuthenticationError { mechanism, address });
+            }
+            if let Err(error) = zap.send_multipart(reply, 0) {
+                self.error_server_addr.do_send(SocketSecurityError(error));
+            }
+            if let Some(readiness) = &self.zap_readiness {
+                readiness.sent();
+            }
+        }
+    }
+
+    // Sleeps until the socket or the ZAP handler has something to read, then reads it.
+    fn wait(&mut self, ctx: &mut Context<Self>) {
+        ctx.spawn(Readable.map(|result, act, ctx| match result {
+            Ok(()) => ctx.notify(Recv),
+            Err(error) => {
+                act.error_server_addr.do_send(SocketWaitError {
+                    error,
+                    host: act.host.clone(),
+                    port: act.port,
+                });
+                ctx.stop();
+            }
+        }));
+    }
+
+    // Reads a whole message, along with the user ID its connection was given when it was let in.
+    fn recv(&self) -> Result<(Vec<Vec<u8>>, String), zmq::Error> {
+        let socket = self.socket.as_ref().unwrap();
+        let mut first = socket.recv_msg(zmq::DONTWAIT)?;
+        let user = first.gets("User-Id").unwrap_or_default().to_owned();
+        let mut frames = vec![first.to_vec()];
+        if socket.get_rcvmore()? {
+            frames.extend(socket.recv_multipart(0)?);
+        }
+        Ok((frames, user))
+    }
+
+    // Checks the request against the ACL, resolving to the error to answer with if it's turned
+    // down.
+    fn authorize(&self, origin: &Origin, inner: &m::wire_message::Inner) -> Result<(), cm::wire_message::Inner> {
+        let acl = match &self.acl {
+            Some(acl) => acl,
+            None => return Ok(()),
+        };
+        for Access { permission, namespaced, name } in access(inner) {
+            let namespace = if namespaced { origin.namespace.as_str() } else { "" };
+            if acl.allows(&origin.user, permission, namespace, name) {
+                continue;
+            }
+
+            self.error_server_addr.do_send(PermissionDeniedError {
+                user: origin.user.clone(),
+                permission,
+                namespace: namespace.to_owned(),
+                name: name.map(str::to_owned),
+            });
+            let message = match name {
+                Some(name) => format!(
+                    "User {:?} does not have {} permission on {:?} in namespace {:?}",
+                    origin.user, permission, name, namespace
+                ),
+                None => format!(
+                    "User {:?} does not have {} permission on namespace {:?}",
+                    origin.user, permission, namespace
+                ),
+            };
+            return Err(error(cm::ErrorCode::PermissionDenied, message));
+        }
+        Ok(())
+    }
+
+    // Handles a message read off the socket; the last frame is the payload, and everything in
+    // front of it is the envelope needed to route a reply.  Tries to deserialize the payload
+    // according to our message format.
+    fn handle_message(&mut self, mut envelope: Envelope, user: String, ctx: &mut Context<Self>) {
+        let bytes = envelope.pop().unwrap_or_default();
+        match m::WireMessage::decode(Cursor::new(&bytes)) {
+            Ok(m::WireMessage {
+                id,
+                sequence,
+                namespace,
+                inner: Some(inner),
+            }) => {
+                let origin = Origin {
+                    envelope,
+                    session_id: id,
+                    sequence,
+                    namespace,
+                    user,
+                };
+                let session = self.sessions.get(&id);
+                match inner {
+                    inner @ m::wire_message::Inner::Hello(_) => {
+                        self.dispatch(origin, inner, ctx)
+                    }
+                    // Only the client a session was handed to may use it.  Requests go
+                    // to the session's namespace unless they name another.
+                    inner
+                        if session.map(|session| &session.identity)
+                            == origin.envelope.first() =>
+                    {
+                        let session = self.sessions.get_mut(&id).unwrap();
+                        session.last_seen = Instant::now();
+                        let origin = if origin.namespace.is_empty() {
+                            Origin {
+                                namespace: session.namespace.clone(),
+                                ..origin
+                            }
+                        } else {
+                            origin
+                        };
+                        self.dispatch(origin, inner, ctx)
+                    }
+                    // Clients from before the handshake send requests without ever
+                    // saying Hello; tell them plainly that they need upgrading.
+                    _ if id == 0 => self.unsupported_version(origin, String::new(), 0),
+                    _ => self.respond(
+                        origin,
+                        error(
+                            cm::ErrorCode::UnknownSession,
+                            format!("Session {} was not handed out to this client", id),
+                        ),
+                    ),
+                }
+            }
+            // Most likely a newer client asking for something this server doesn't know
+            // about, since unknown fields are skipped when decoding.  Not fatal at all;
+            // tell the client and move on.
+            Ok(m::WireMessage {
+                id,
+                sequence,
+                inner: None,
+                ..
+            }) => {
+                self.error_server_addr
+                    .do_send(MessageDecodeError(None, bytes));
+
+                let origin = Origin {
+                    envelope,
+                    session_id: id,
+                    sequence,
+                    namespace: String::new(),
+                    user,
+                };
+                let message = "The request did not say what to do, or asked for something this server does not support".to_owned();
+                self.respond(origin, error(cm::ErrorCode::EmptyRequest, message));
+            }
+            // A message we can't decode should be logged, but there's nothing critical
+            // here.  Processes are free to send us malformed messages over this socket;
+            // all we can do is tell them so, without knowing which request it was.
+            Err(decode_error) => {
+                let origin = Origin {
+                    envelope,
+                    session_id: 0,
+                    sequence: 0,
+                    namespace: String::new(),
+                    user,
+                };
+                let message = decode_error.to_string();
+                self.respond(origin, error(cm::ErrorCode::DecodeFailed, message));
+
+                self.error_server_addr
+                    .do_send(MessageDecodeError(Some(decode_error), bytes));
+            }
+        }
+    }
+
+    // Has the server exit with the status, having given up on the socket.
+    fn fail(&mut self, status: i32) {
+        if let Some(failed) = self.failed.take() {
+            let _ = failed.send(status);
+        }
+    }
+
+    // Forgets the sessions that have gone unused for too long.
+    fn expire_sessions(&mut self) {
+        let now = Instant::now();
+        self.sessions
+            .retain(|_, session| now.duration_since(session.last_seen) < SESSION_IDLE_TIMEOUT);
+    }
+
+    fn unsupported_version(&self, origin: Origin, client_name: String, protocol_version: u32) {
+        self.error_server_addr.do_send(UnsupportedVersionError {
+            client_name,
+            protocol_version,
+        });
+        self.respond(
+            origin,
+            cm::wire_message::Inner::UnsupportedVersion(cm::UnsupportedVersion {
+                min_version: MIN_PROTOCOL_VERSION,
+                max_version: PROTOCOL_VERSION,
+            }),
+        );
+    }
+
+    // Hands out a new session ID, tied to the identity of the client that asked for it, if we
+    // speak the client's protocol version.
+    fn hello(
+        &mut self,
+        origin: Origin,
+        m::Hello {
+            protocol_version,
+            client_name,
+            capabilities,
+            namespace,
+        }: m::Hello,
+    ) {
+        if protocol_version < MIN_PROTOCOL_VERSION || protocol_version > PROTOCOL_VERSION {
+            return self.unsupported_version(origin, client_name, protocol_version);
+        }
+
+        let identity = origin.envelope.first().cloned().unwrap_or_default();
+
+        // 0 means "no session", and IDs still in use are skipped once the counter wraps around.
+        while self.next_session_id == 0 || self.sessions.contains_key(&self.next_session_id) {
+            self.next_session_id = self.next_session_id.wrapping_add(1);
+        }
+        let session_id = self.next_session_id;
+        self.next_session_id = self.next_session_id.wrapping_add(1);
+        self.sessions.insert(
+            session_id,
+            Session {
+                identity,
+                namespace: namespace.clone(),
+                last_seen: Instant::now(),
+            },
+        );
+
+        let origin = Origin {
+            session_id,
+            ..origin
+        };
+        let capabilities = capabilities
+            .into_iter()
+            .filter(|capability| CAPABILITIES.contains(&capability.as_str()))
+            .collect();
+        self.respond(
+            origin,
+            cm::wire_message::Inner::Welcome(cm::Welcome {
+                session_id,
+                protocol_version,
+                capabilities,
+                namespace,
+            }),
+        );
+    }
+
+    fn dispatch(&mut self, origin: Origin, inner: m::wire_message::Inner, ctx: &mut Context<Self>) {
+        use cm::wire_message::Inner as Reply;
+        use m::wire_message::Inner as Request;
+
+        if let Err(denied) = self.authorize(&origin, &inner) {
+            return self.respond(origin, denied);
+        }
+
+        match inner {
+            Request::Hello(hello) => self.hello(origin, hello),
+            Request::SetInsert(m::SetInsert { name, value }) => {
+                let request = self.agents.set.shard(&origin.namespace, &name).send(set::Insert {
+                    namespace: origin.namespace.clone(),
+                    name,
+                    value,
+                });
+                self.reply_with(ctx, request, origin, |result| match result {
+                    Ok(inserted) => Reply::SetInsertResult(cm::SetInsertResult { inserted }),
+                    Err(error) => set_error(error),
+                })
+            }
+            Request::SetInsertMany(m::SetInsertMany { name, values }) => {
+                let request = self.agents.set.shard(&origin.namespace, &name).send(set::InsertMany {
+                    namespace: origin.namespace.clone(),
+                    name,
+                    values,
+                });
+                self.reply_with(ctx, request, origin, |result| match result {
+                    Ok(inserted) => {
+                        let mut bitmap = vec![0u8; (inserted.len() + 7) / 8];
+                        for (i, _) in inserted.iter().enumerate().filter(|(_, inserted)| **inserted) {
+                            bitmap[i / 8] |= 1 << (i % 8);
+                        }
+                        Reply::SetInsertManyResult(cm::SetInsertManyResult { inserted: bitmap })
+                    }
+                    Err(error) => set_error(error),
+                })
+            }
+            Request::SetMove(m::SetMove {
+                source,
+                destination,
+                member,
+            }) => {
+                let request = self.agents.set.move_member(set::Move {
+                    namespace: origin.namespace.clone(),
+                    source,
+                    destination,
+                    value: member,
+                });
+                self.reply_with(ctx, request, origin, |result| match result {
+                    Ok(moved) => Reply::SetMoveResult(cm::SetMoveResult { moved }),
+                    Err(error) => set_error(error),
+                })
+            }
+            Request::InsertUnless(m::InsertUnless {
+                name,
+                member,
+                unless_in,
+            }) => {
+                let request = self.agents.set.insert_unless(set::InsertUnless {
+                    namespace: origin.namespace.clone(),
+                    name,
+                    value: member,
+                    unless_in,
+                });
+                self.reply_with(ctx, request, origin, |result| match result {
+                    Ok(inserted) => Reply::InsertUnlessResult(cm::InsertUnlessResult { inserted }),
+                    Err(error) => set_error(error),
+                })
+            }
+            Request::SetScan(m::SetScan {
+                name,
+                cursor,
+                count,
+                prefix,
+                pattern,
+            }) => {
+                let after = match cursor.split_first() {
+                    None => None,
+                    Some((&SCAN_CURSOR_MARKER, after)) => Some(after.to_vec()),
+                    Some(_) => {
+                        let message = "The cursor did not come from a scan".to_owned();
+                        return self.respond(origin, error(cm::ErrorCode::InvalidArgument, message));
+                    }
+                };
+                let request = self.agents.set.shard(&origin.namespace, &name).send(set::Scan {
+                    namespace: origin.namespace.clone(),
+                    name,
+                    after,
+                    count: if count == 0 { DEFAULT_SCAN_COUNT } else { count as usize },
+                    prefix,
+                    pattern: if pattern.is_empty() { None } else { Some(pattern) },
+                });
+                self.reply_with(ctx, request, origin, |result| match result {
+                    Ok((members, next)) => Reply::SetScanResult(cm::SetScanResult {
+                        members,
+                        cursor: match next {
+                            None => vec![],
+                            Some(next) => {
+                                let mut cursor = vec![SCAN_CURSOR_MARKER];
+                                cursor.extend(next);
+                                cursor
+                            }
+                        },
+                    }),
+                    Err(error) => set_error(error),
+                })
+            }
+            Request::SetRandMember(m::SetRandMember {
+                name,
+                count,
+                allow_duplicates,
+            }) => {
+                if allow_duplicates && count > MAX_RAND_MEMBERS {
+                    let message = format!("At most {} members can be picked with duplicates", MAX_RAND_MEMBERS);
+                    return self.respond(origin, error(cm::ErrorCode::InvalidArgument, message));
+                }
+                let request = self.agents.set.shard(&origin.namespace, &name).send(set::RandMember {
+                    namespace: origin.namespace.clone(),
+                    name,
+                    count: count.max(1) as usize,
+                    allow_duplicates,
+                });
+                self.reply_with(ctx, request, origin, |result| match result {
+                    Ok(members) => Reply::SetRandMemberResult(cm::SetRandMemberResult { members }),
+                    Err(error) => set_error(error),
+                })
+            }
+            Request::SetPop(m::SetPop { name, count }) => {
+                let request = self.agents.set.shard(&origin.namespace, &name).send(set::Pop {
+                    namespace: origin.namespace.clone(),
+                    name,
+                    count: count.max(1) as usize,
+                });
+                self.reply_with(ctx, request, origin, |result| match result {
+                    Ok(members) => Reply::SetPopResult(cm::SetPopResult { members }),
+                    Err(error) => set_error(error),
+                })
+            }
+            Request::Multi(m::Multi { ops, watches }) => {
+                let mut set_ops = Vec::with_capacity(ops.len());
+                for (i, m::Op { op }) in ops.into_iter().enumerate() {
+                    set_ops.push(match op {
+                        Some(m::op::Op::Insert(m::SetInsert { name, value })) => {
+                            set::Op::Insert { name, value }
+                        }
+                        Some(m::op::Op::Remove(m::SetRemove { name, value })) => {
+                            set::Op::Remove { name, value }
+                        }
+                        Some(m::op::Op::Increment(m::MultisetInsert { name, value })) => {
+                            set::Op::Increment { name, value }
+                        }
+                        Some(m::op::Op::Version(m::SetVersion { name })) => set::Op::Version { name },
+                        None => {
+                            let message = format!("Operation {} of the transaction is empty", i);
+                            return self.respond(origin, error(cm::ErrorCode::InvalidArgument, message));
+                        }
+                    });
+                }
+                let request = self.agents.set.multi(set::Multi {
+                    namespace: origin.namespace.clone(),
+                    ops: set_ops,
+                    watches: watches
+                        .into_iter()
+                        .map(|m::Watch { name, version }| (name, version))
+                        .collect(),
+                });
+                self.reply_with(ctx, request, origin, |result| match result {
+                    Ok(results) => Reply::MultiResult(cm::MultiResult {
+                        results: results
+                            .into_iter()
+                            .map(|result| cm::OpResult {
+                                result: Some(match result {
+                                    set::OpResult::Inserted(inserted) => {
+                                        cm::op_result::Result::Inserted(inserted)
+                                    }
+                                    set::OpResult::Removed(removed) => {
+                                        cm::op_result::Result::Removed(removed)
+                                    }
+                                    set::OpResult::Count(count) => cm::op_result::Result::Count(count),
+                                    set::OpResult::Version(version) => {
+                                        cm::op_result::Result::Version(version)
+                                    }
+                                }),
+                            })
+                            .collect(),
+                    }),
+                    Err(error) => set_error(error),
+                })
+            }
+            Request::ListNames(m::ListNames { pattern }) => {
+                let pattern = if pattern.is_empty() { None } else { Some(pattern) };
+                let request = self.agents.set.list_names(origin.namespace.clone(), pattern);
+                self.reply_with(ctx, request, origin, |names| {
+                    Reply::ListNamesResult(cm::ListNamesResult { names })
+                })
+            }
+            Request::FlushNamespace(m::FlushNamespace {}) => {
+                let request = self.agents.set.flush(origin.namespace.clone());
+                self.reply_with(ctx, request, origin, |removed| {
+                    Reply::FlushNamespaceResult(cm::FlushNamespaceResult { removed })
+                })
+            }
+            Request::SetQuota(m::SetQuota { max_members }) => {
+                let quota = if max_members == 0 { None } else { Some(max_members) };
+                let members = self.agents.set.set_quota(&origin.namespace, quota);
+                self.respond(origin, Reply::SetQuotaResult(cm::SetQuotaResult { members }))
+            }
+            Request::SetCreate(m::SetCreate {
+                name,
+                max_members,
+                overflow,
+            }) => {
+                let overflow = match m::Overflow::from_i32(overflow) {
+                    Some(m::Overflow::Reject) => set::Overflow::Reject,
+                    Some(m::Overflow::EvictOldest) => set::Overflow::EvictOldest,
+                    Some(m::Overflow::EvictRandom) => set::Overflow::EvictRandom,
+                    None => {
+                        let message = format!("{} is not an overflow policy", overflow);
+                        return self.respond(origin, error(cm::ErrorCode::InvalidArgument, message));
+                    }
+                };
+                if max_members == 0 {
+                    let message = "A capped set has to hold at least 1 member".to_owned();
+                    return self.respond(origin, error(cm::ErrorCode::InvalidArgument, message));
+                }
+                let request = self.agents.set.shard(&origin.namespace, &name).send(set::Create {
+                    namespace: origin.namespace.clone(),
+                    name,
+                    max_members,
+                    overflow,
+                });
+                self.reply_with(ctx, request, origin, |result| match result {
+                    Ok(created) => Reply::SetCreateResult(cm::SetCreateResult { created }),
+                    Err(error) => set_error(error),
+                })
+            }
+            Request::SetExpire(m::SetExpire { name, ttl_ms }) => {
+                let request = self.agents.set.shard(&origin.namespace, &name).send(set::Expire {
+                    namespace: origin.namespace.clone(),
+                    name,
+                    ttl: if ttl_ms == 0 { None } else { Some(Duration::from_millis(ttl_ms)) },
+                });
+                self.reply_with(ctx, request, origin, |set| {
+                    Reply::SetExpireResult(cm::SetExpireResult { set })
+                })
+            }
+            Request::MultisetInsert(m::MultisetInsert { name, value }) => {
+                let request = self.agents.set.shard(&origin.namespace, &name).send(set::MultisetInsert {
+                    namespace: origin.namespace.clone(),
+                    name,
+                    value,
+                });
+                self.reply_with(ctx, request, origin, |result| match result {
+                    Ok(count) => Reply::MultisetInsertResult(cm::MultisetInsertResult { count }),
+                    Err(error) => set_error(error),
+                })
+            }
+            Request::MultisetMembers(m::MultisetMembers { name }) => {
+                let request = self.agents.set.shard(&origin.namespace, &name).send(set::MultisetMembers {
+                    namespace: origin.namespace.clone(),
+                    name,
+                });
+                self.reply_with(ctx, request, origin, |result| match result {
+                    Ok(members) => Reply::MultisetMembersResult(cm::MultisetMembersResult {
+                        members: members
+                            .into_iter()
+                            .map(|(value, count)| cm::ValueCount { value, count })
+                            .collect(),
+                    }),
+                    Err(error) => set_error(error),
+                })
+            }
+            Request::SetBit(m::SetBit {
+                name,
+                offset,
+                value,
+            }) => {
+                let request = self.agents.bitmap.send(bitmap::SetBit { name, offset, value });
+                self.reply_with(ctx, request, origin, |previous| {
+                    Reply::SetBitResult(cm::SetBitResult { previous })
+                })
+            }
+            Request::GetBit(m::GetBit { name, offset }) => {
+                let request = self.agents.bitmap.send(bitmap::GetBit { name, offset });
+                self.reply_with(ctx, request, origin, |value| {
+                    Reply::GetBitResult(cm::GetBitResult { value })
+                })
+            }
+            Request::BitCount(m::BitCount { name }) => {
+                let request = self.agents.bitmap.send(bitmap::BitCount { name });
+                self.reply_with(ctx, request, origin, |count| {
+                    Reply::BitCountResult(cm::BitCountResult { count })
+                })
+            }
+            Request::BitOp(m::BitOp {
+                operation,
+                destination,
+                sources,
+                size,
+            }) => {
+                let operation = match m::BitOperation::from_i32(operation) {
+                    Some(m::BitOperation::And) => bitmap::Operation::And,
+                    Some(m::BitOperation::Or) => bitmap::Operation::Or,
+                    Some(m::BitOperation::Xor) => bitmap::Operation::Xor,
+                    Some(m::BitOperation::Not) => bitmap::Operation::Not,
+                    None => {
+                        let message = format!("{} is not a bitwise operation", operation);
+                        return self.respond(origin, error(cm::ErrorCode::InvalidArgument, message));
+                    }
+                };
+                let request = self.agents.bitmap.send(bitmap::BitOp {
+                    operation,
+                    destination,
+                    sources,
+                    size,
+                });
+                self.reply_with(ctx, request, origin, |result| match result {
+                    Ok(count) => Reply::BitOpResult(cm::BitOpResult { count }),
+                    Err(error) => bitmap_error(error),
+                })
+            }
+            Request::Acquire(m::Acquire {
+                name,
+                rate,
+                burst,
+                cost,
+                block,
+            }) => {
+                if ratelimit::intervals(rate, burst, cost).is_none() {
+                    return self.respond(origin, rate_limit_error(RateLimitError::Invalid));
+                }
+                let request = self.agents.rate_limit.send(ratelimit::Acquire {
+                    name,
+                    rate,
+                    burst,
+                    cost,
+                    block,
+                });
+                // Hold on to the reply until a reservation comes due, without holding up anyone
+                // else.
+                let request = async move {
+                    let acquired = request.await?;
+                    if let Ok(Acquired::Reserved { wait }) = acquired {
+                        delay_for(wait).await;
+                    }
+                    Ok(acquired)
+                };
+                self.reply_with(ctx, request, origin, |acquired| match acquired {
+                    Ok(acquired) => Reply::AcquireResult(match acquired {
+                        Acquired::Allowed | Acquired::Reserved { .. } => cm::AcquireResult {
+                            allowed: true,
+                            retry_after_ms: 0,
+                        },
+                        Acquired::Denied { retry_after } => cm::AcquireResult {
+                            allowed: false,
+                            retry_after_ms: retry_after.as_millis() as u64,
+                        },
+                    }),
+                    Err(error) => rate_limit_error(error),
+                })
+            }
+            Request::StreamAppend(m::StreamAppend { name, value }) => {
+                let request = self.agents.stream.send(stream::Append { name, value });
+                self.reply_with(ctx, request, origin, |id| {
+                    Reply::StreamAppendResult(cm::StreamAppendResult { id })
+                })
+            }
+            Request::StreamRead(m::StreamRead {
+                name,
+                group,
+                after,
+                count,
+                block_ms,
+            }) => {
+                let request = self.agents.stream.send(stream::Read {
+                    name,
+                    group: if group.is_empty() { None } else { Some(group) },
+                    after,
+                    count: if count == 0 { usize::MAX } else { count as usize },
+                    block: block_ms > 0,
+                });
+                // A blocked read answers with whatever was appended first, or nothing at all if
+                // the wait runs out.
+                let request = async move {
+                    Ok(match request.await? {
+                        ReadResult::Entries(entries) => entries,
+                        ReadResult::Wait(receiver) => {
+                            match timeout(Duration::from_millis(block_ms), receiver).await {
+                                Ok(Ok(entries)) => entries,
+                                _ => vec![],
+                            }
+                        }
+                    })
+                };
+                self.reply_with(ctx, request, origin, |entries| {
+                    Reply::StreamReadResult(cm::StreamReadResult {
+                        entries: entries
+                            .into_iter()
+                            .map(|(id, value)| cm::StreamEntry { id, value })
+                            .collect(),
+                    })
+                })
+            }
+            Request::StreamCommit(m::StreamCommit { name, group, id }) => {
+                let request = self.agents.stream.send(stream::Commit { name, group, id });
+                self.reply_with(ctx, request, origin, |result| match result {
+                    Ok(id) => Reply::StreamCommitResult(cm::StreamCommitResult { id }),
+                    Err(error) => stream_error(error),
+                })
+            }
+            Request::BfReserve(m::BfReserve {
+                name,
+                capacity,
+                error_rate,
+            }) => {
+                let request = self.agents.bloom.send(bloom::Reserve {
+                    name,
+                    capacity,
+                    error_rate,
+                });
+                self.reply_with(ctx, request, origin, |result| match result {
+                    Ok(created) => Reply::BfReserveResult(cm::BfReserveResult { created }),
+                    Err(error) => bloom_error(error),
+                })
+            }
+            Request::BfAdd(m::BfAdd { name, value }) => {
+                let request = self.agents.bloom.send(bloom::Add { name, value });
+                self.reply_with(ctx, request, origin, |added| {
+                    Reply::BfAddResult(cm::BfAddResult { added })
+                })
+            }
+            Request::BfExists(m::BfExists { name, value }) => {
+                let request = self.agents.bloom.send(bloom::Exists { name, value });
+                self.reply_with(ctx, request, origin, |exists| {
+                    Reply::BfExistsResult(cm::BfExistsResult { exists })
+                })
+            }
+            Request::CmsIncr(m::CmsIncr {
+                name,
+                value,
+                increment,
+            }) => {
+                let request = self.agents.sketch.send(sketch::Incr {
+                    name,
+                    value,
+                    increment,
+                });
+                self.reply_with(ctx, request, origin, |count| {
+                    Reply::CmsIncrResult(cm::CmsIncrResult { count })
+                })
+            }
+            Request::CmsQuery(m::CmsQuery { name, value }) => {
+                let request = self.agents.sketch.send(sketch::Query { name, value });
+                self.reply_with(ctx, request, origin, |count| {
+                    Reply::CmsQueryResult(cm::CmsQueryResult { count })
+                })
+            }
+            Request::TopKAdd(m::TopKAdd { name, k, value }) => {
+                let request = self.agents.sketch.send(sketch::TopKAdd { name, k, value });
+                self.reply_with(ctx, request, origin, |count| {
+                    Reply::TopKAddResult(cm::TopKAddResult { count })
+                })
+            }
+            Request::TopKList(m::TopKList { name }) => {
+                let request = self.agents.sketch.send(sketch::TopKList { name });
+                self.reply_with(ctx, request, origin, |members| {
+                    Reply::TopKListResult(cm::TopKListResult {
+                        members: members
+                            .into_iter()
+                            .map(|(value, count)| cm::ValueCount { value, count })
+                            .collect(),
+                    })
+                })
+            }
+        }
+    }
+}
 
-    fn started(&mut self, _ctx: &mut Self::Context) {
+impl MessengerServer {
+    // Opens the socket and starts reading from it, stopping the actor for the supervisor to try
+    // again if anything goes wrong.
+    fn open(&mut self, ctx: &mut Context<Self>) {
         match self.ctx.socket(zmq::SocketType::ROUTER) {
-            Err(error) => self.error_server_addr.do_send(SocketOpenError(error)),
+            Err(error) => {
+                self.error_server_addr.do_send(SocketOpenError(error));
+                ctx.stop();
+            }
             Ok(socket) => {
+                if let Err(error) = self.auth.configure(&socket) {
+                    self.error_server_addr.do_send(SocketSecurityError(error));
+                    return ctx.stop();
+                }
+                // The handler has to be listening before the first client connects, or that
+                // client is let in unchecked.
+                if self.auth.needs_handler() {
+                    let zap = self
+                        .ctx
+                        .socket(zmq::SocketType::REP)
+                        .and_then(|zap| zap.bind(ZAP_ENDPOINT).map(|()| zap));
+                    match zap {
+                        Ok(zap) => self.zap = Some(zap),
+                        Err(error) => {
+                            self.error_server_addr.do_send(SocketSecurityError(error));
+                            return ctx.stop();
+                        }
+                    }
+                }
+
                 self.socket = Some(socket);
                 if let Err(error) = self
                     .socket
                     .as_ref()
                     .unwrap()
-                    .connect(&format!("tcp://{}:{}", self.host, self.port))
+                    .bind(&format!("tcp://{}:{}", self.host, self.port))
                 {
                     self.error_server_addr.do_send(SocketConnectionError {
                         error,
                         host: self.host.clone(),
                         port: self.port,
-                    })
+                    });
+                    return ctx.stop();
+                }
+
+                let readiness = Readiness::new(self.socket.as_ref().unwrap()).and_then(|readiness| {
+                    let zap_readiness = self.zap.as_ref().map(Readiness::new).transpose()?;
+                    Ok((readiness, zap_readiness))
+                });
+                match readiness {
+                    Ok((readiness, zap_readiness)) => {
+                        self.readiness = Some(readiness);
+                        self.zap_readiness = zap_readiness;
+                        if let Some(ready) = self.ready.take() {
+                            let _ = ready.send(());
+                        }
+                        ctx.notify(Recv);
+                    }
+                    Err(error) => {
+                        self.error_server_addr.do_send(SocketWaitError {
+                            error,
+                            host: self.host.clone(),
+                            port: self.port,
+                        });
+                        ctx.stop();
+                    }
                 }
             }
         }
     }
 }
 
-impl Handler<set::Insert> for MessengerServer {
-    type Result = bool;
+impl Actor for MessengerServer {
+    type Context = Context<Self>;
 
-    fn handle(&mut self, insert: set::Insert, ctx: &mut Context<Self>) -> Self::Result {
-        match self.set_agent_addr.try_send(insert) {
-            Err(SendError::Closed(insert)) => {
-                ctx.address().do_send(insert);
-                false
-            }
-            Err(SendError::Full(insert)) => {
-                ctx.address().do_send(insert);
-                false
-            }
-            Ok(()) => true,
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(SESSION_SWEEP_INTERVAL, |act, _ctx| act.expire_sessions());
+        if self.restarts == 0 {
+            return self.open(ctx);
+        }
+
+        // Failing before the socket was ever open is down to how the server was started, e.g. with
+        // a port that's taken, which trying again won't fix; only failures after are worth it.
+        let gave_up = self.restarts > MAX_RESTARTS || self.ready.is_some();
+        let delay = (FIRST_RESTART_DELAY * 2u32.pow((self.restarts - 1).min(16))).min(MAX_RESTART_DELAY);
+        let report = self.error_server_addr.send(MessengerRestart {
+            restarts: self.restarts,
+            delay: if gave_up { None } else { Some(delay) },
+            host: self.host.clone(),
+            port: self.port,
+        });
+        if gave_up {
+            // Waiting for the report to be logged before going.
+            ctx.wait(report.into_actor(self).map(|_, act, _| act.fail(EXIT_UNAVAILABLE)));
+            return;
         }
+        ctx.run_later(delay, |act, ctx| act.open(ctx));
     }
 }
 
+// Run under a supervisor, which starts the actor again whenever it stops.  Sessions carry over,
+// but requests still waiting on an agent go unanswered.
+impl Supervised for MessengerServer {
+    fn restarting(&mut self, _ctx: &mut Context<Self>) {
+        // Not waiting on anything still queued to go out, which would keep the context from
+        // closing.
+        for socket in self.socket.iter().chain(self.zap.iter()) {
+            let _ = socket.set_linger(0);
+        }
+        self.readiness = None;
+        self.zap_readiness = None;
+        self.socket = None;
+        self.zap = None;
+        // In case it was the context that went bad.
+        self.ctx = zmq::Context::new();
+        self.restarts += 1;
+        // Whatever was in flight went with the old context.
+        self.in_flight = 0;
+        self.check_drained();
+    }
+}
+
+/// Stops reading requests, telling the sender once every request already read has been
+/// answered.  Requests still arriving are left unread, for their clients to time out on.
 #[derive(actix::Message)]
 #[rtype(result = "()")]
-enum Error {
-    InvalidMessage { id: u32 },
-}
+pub struct Drain(pub oneshot::Sender<()>);
 
-impl Handler<Error> for MessengerServer {
+impl Handler<Drain> for MessengerServer {
     type Result = ();
 
-    fn handle(&mut self, error: Error, ctx: &mut Context<Self>) -> Self::Result {
-        match error {
-            Error::InvalidMessage { id } => match &self.socket {
-                Some(socket) => {
-                    let mut buf = vec![];
-                    cm::WireMessage {
-                        id,
-                        inner: Some(cm::wire_message::Inner::UnrecognizedMessageError(cm::UnrecognizedMessageError{})),
-                    }.encode(&mut buf);
-
-                    match socket.send(buf, 0) {
-                        // TODO: What should happen here?
-                        Err(error) => {},
-                        Ok(()) => (),
-                    };
-                },
-                // Log that a client would not have received a response to their request.
-                // Not much more we can do there.  Clients should have a timeout due to the
-                // possibility of encountering an error like this.
-                None => self.error_server_addr.do_send(UnsentResponseError {
-                    client_id: id,
-                    host: self.host.clone(),
-                    port: self.port,
-                }),
-            },
-        }
+    fn handle(&mut self, Drain(drained): Drain, _ctx: &mut Context<Self>) -> Self::Result {
+        self.draining = true;
+        self.drained = Some(drained);
+        self.check_drained();
     }
 }
 
@@ -124,62 +1327,71 @@ impl Handler<Error> for MessengerServer {
 #[rtype(result = "()")]
 struct Recv;
 
+// Resolves once the socket or the ZAP handler has something to read.
+struct Readable;
+
+impl ActorFuture for Readable {
+    type Output = io::Result<()>;
+    type Actor = MessengerServer;
+
+    fn poll(
+        self: Pin<&mut Self>,
+        act: &mut MessengerServer,
+        _ctx: &mut Context<MessengerServer>,
+        task: &mut task::Context,
+    ) -> Poll<Self::Output> {
+        if let (Some(zap), Some(readiness)) = (&act.zap, &act.zap_readiness) {
+            if let Poll::Ready(result) = readiness.poll_readable(zap, task) {
+                return Poll::Ready(result);
+            }
+        }
+        match (&act.socket, &act.readiness) {
+            (Some(socket), Some(readiness)) => readiness.poll_readable(socket, task),
+            _ => Poll::Pending,
+        }
+    }
+}
+
 impl Handler<Recv> for MessengerServer {
     type Result = ();
 
-    // The main read loop of this actor.  Waits for incoming messages over the zeromq socket, and
-    // dispatches actions to the data structure agents and sends responses to connected clients on
-    // errors.
+    // The main read loop of this actor.  Reads the messages waiting on the zeromq socket,
+    // dispatching actions to the data structure agents and sending responses to connected clients
+    // on errors, then sleeps until more arrive.
     fn handle(&mut self, _: Recv, ctx: &mut Context<Self>) -> Self::Result {
-        // See http://api.zeromq.org/master:zmq-recv for an overview of error types.
-        match self.socket.as_ref().unwrap().recv_bytes(zmq::DONTWAIT) {
-            // EAGAIN, with the DONTWAIT flag set, indicates that there is no data.  This is not
-            // considered an error.
-            Err(zmq::Error::EAGAIN) => (),
-            // If the zmq process was interrupted with a signal, retry; if the signal should kill
-            // this process too, we'll know soon enough.
-            Err(zmq::Error::EINTR) => (),
-            // ETERM/ENOTSOCK: The context was terminated, or something got the socket into a bad
-            // state; the actor must be restarted for messages to be received properly.
-            // Other errors should be handled as an exceptional event; still, let it crash.
-            Err(error) => {
-                self.error_server_addr.do_send(SocketRecvError {
-                    error,
-                    host: self.host.clone(),
-                    port: self.port,
-                });
+        if self.draining {
+            return;
+        }
+        self.authenticate();
 
-                ctx.stop();
-            }
-            // Received a message; try to deserialize it according to our message format.
-            Ok(bytes) => match m::WireMessage::decode(Cursor::new(&bytes)) {
-                // Queue up a message to tell us to insert the given data.
-                Ok(m::WireMessage {
-                    id,
-                    inner: Some(m::wire_message::Inner::SetInsert(m::SetInsert { name, value })),
-                }) => ctx.address().do_send(set::Insert { id, name, value }),
-                // No idea what context this would happen in, but it's not fatal at all.
-                // Log the error and move on.
-                Ok(m::WireMessage { id, inner: None }) => {
-                    self.error_server_addr
-                        .do_send(MessageDecodeError(None, bytes));
-
-                    // TODO: Send a failure message to the server.
+        // See http://api.zeromq.org/master:zmq-recv for an overview of error types.
+        for _ in 0..RECV_BATCH {
+            match self.recv() {
+                Ok((envelope, user)) => {
+                    self.restarts = 0;
+                    self.handle_message(envelope, user, ctx)
                 }
-                // A message we can't decode should be logged, but there's nothing critical here.
-                // Processes are free to send us malformed messages over this socket, and we are
-                // free to ignore them.
-                Err(decode_error) => {
-                    self.error_server_addr
-                        .do_send(MessageDecodeError(Some(decode_error), bytes));
+                // EAGAIN, with the DONTWAIT flag set, indicates that there is no data left.
+                Err(zmq::Error::EAGAIN) => return self.wait(ctx),
+                // If the zmq process was interrupted with a signal, retry; if the signal should
+                // kill this process too, we'll know soon enough.
+                Err(zmq::Error::EINTR) => (),
+                // ETERM/ENOTSOCK: The context was terminated, or something got the socket into a
+                // bad state; the actor must be restarted for messages to be received properly.
+                // Other errors should be handled as an exceptional event; still, let it crash,
+                // and the supervisor opens a new socket.
+                Err(error) => {
+                    self.error_server_addr.do_send(SocketRecvError {
+                        error,
+                        host: self.host.clone(),
+                        port: self.port,
+                    });
+                    return ctx.stop();
                 }
-            },
-        };
+            }
+        }
 
-        // Loop again, sending Recvs until a signal is queued up that kills the actor.
-        match ctx.address().try_send(Recv) {
-            Err(SendError::Closed(_)) => (),
-            _ => ctx.address().do_send(Recv),
-        };
+        // There's more to read, but let the agents' answers through first.
+        ctx.notify(Recv);
     }
 }
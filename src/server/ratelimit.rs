@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+
+use actix::{Actor, Context, Handler, Message};
+
+/// Rate limiters implementing the generic cell rate algorithm.  Each limiter only needs to
+/// remember its theoretical arrival time: the moment it would be back to a full burst if nothing
+/// else were acquired.  The rate and burst come with every request, so callers sharing a name
+/// should agree on them.
+pub struct RateLimitAgent {
+    data: HashMap<String, Instant>,
+}
+
+impl RateLimitAgent {
+    pub fn new() -> RateLimitAgent {
+        RateLimitAgent {
+            data: HashMap::new(),
+        }
+    }
+
+    // Takes tokens costing `increment` from the limiter as of `now`, with up to `tolerance` of
+    // them available at once.
+    fn acquire(
+        &mut self,
+        name: String,
+        (increment, tolerance): (Duration, Duration),
+        block: bool,
+        now: Instant,
+    ) -> Result<Acquired, RateLimitError> {
+        let tat = self.data.get(&name).copied().unwrap_or(now).max(now);
+        let new_tat = match tat.checked_add(increment) {
+            Some(new_tat) => new_tat,
+            None => return Err(RateLimitError::Backlogged { name }),
+        };
+        let allowed_at = new_tat.checked_sub(tolerance).unwrap_or(now);
+
+        if allowed_at <= now {
+            self.data.insert(name, new_tat);
+            return Ok(Acquired::Allowed);
+        }
+
+        let wait = allowed_at - now;
+        if !block {
+            return Ok(Acquired::Denied { retry_after: wait });
+        }
+
+        // Take our place in line now, so callers arriving later wait behind us.
+        self.data.insert(name, new_tat);
+        Ok(Acquired::Reserved { wait })
+    }
+}
+
+impl Actor for RateLimitAgent {
+    type Context = Context<Self>;
+}
+
+/// How long one token takes to come back at `rate` tokens per second, times `cost` and times
+/// `burst` (at least 1), or None if `rate` isn't a positive number or either is too long to count.
+pub fn intervals(rate: f64, burst: u64, cost: u64) -> Option<(Duration, Duration)> {
+    if !rate.is_finite() || rate <= 0.0 {
+        return None;
+    }
+    // Durations only go up to u64::MAX seconds, which a tiny rate can take one token past.
+    let period = 1.0 / rate;
+    if !period.is_finite() || period >= u64::MAX as f64 {
+        return None;
+    }
+    let emission_interval = Duration::from_secs_f64(period);
+    let increment = emission_interval.checked_mul(u32::try_from(cost).ok()?)?;
+    let tolerance = emission_interval.checked_mul(u32::try_from(burst.max(1)).ok()?)?;
+    Some((increment, tolerance))
+}
+
+pub enum RateLimitError {
+    // The rate, burst or cost were out of range; see `intervals`.
+    Invalid,
+    // Reservations on the limiter have queued up further into the future than can be counted.
+    Backlogged { name: String },
+}
+
+pub enum Acquired {
+    // Go ahead.
+    Allowed,
+    // The tokens are set aside; go ahead once the wait is over.
+    Reserved { wait: Duration },
+    // Not yet; try again after the given delay.
+    Denied { retry_after: Duration },
+}
+
+/// Takes `cost` tokens from a bucket refilling at `rate` tokens per second and holding at most
+/// `burst`.  If there aren't enough tokens, either resolves to `Denied` or, with `block` set,
+/// reserves them and resolves to `Reserved`.  Reservations are handed out in the order they were
+/// asked for.
+#[derive(Message)]
+#[rtype(result = "Result<Acquired, RateLimitError>")]
+pub struct Acquire {
+    pub name: String,
+    pub rate: f64,
+    pub burst: u64,
+    pub cost: u64,
+    pub block: bool,
+}
+
+impl Handler<Acquire> for RateLimitAgent {
+    type Result = Result<Acquired, RateLimitError>;
+
+    fn handle(
+        &mut self,
+        Acquire { name, rate, burst, cost, block }: Acquire,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        let intervals = intervals(rate, burst, cost).ok_or(RateLimitError::Invalid)?;
+        self.acquire(name, intervals, block, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acquire(
+        agent: &mut RateLimitAgent,
+        rate: f64,
+        burst: u64,
+        cost: u64,
+        block: bool,
+        at: Instant,
+    ) -> Acquired {
+        let intervals = intervals(rate, burst, cost).unwrap();
+        match agent.acquire("limiter".to_owned(), intervals, block, at) {
+            Ok(acquired) => acquired,
+            Err(_) => panic!("the limiter was backlogged"),
+        }
+    }
+
+    fn allowed(acquired: Acquired) -> bool {
+        matches!(acquired, Acquired::Allowed)
+    }
+
+    fn retry_after(acquired: Acquired) -> Option<Duration> {
+        match acquired {
+            Acquired::Denied { retry_after } => Some(retry_after),
+            _ => None,
+        }
+    }
+
+    fn wait(acquired: Acquired) -> Option<Duration> {
+        match acquired {
+            Acquired::Reserved { wait } => Some(wait),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn counts_intervals_from_the_rate() {
+        let (increment, tolerance) = intervals(4.0, 3, 2).unwrap();
+        assert_eq!(increment, Duration::from_millis(500));
+        assert_eq!(tolerance, Duration::from_millis(750));
+    }
+
+    #[test]
+    fn treats_a_burst_of_zero_as_one() {
+        assert_eq!(intervals(2.0, 0, 1), intervals(2.0, 1, 1));
+    }
+
+    #[test]
+    fn rejects_rates_that_arent_positive_numbers() {
+        for rate in &[0.0, -1.0, f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(intervals(*rate, 1, 1).is_none());
+        }
+    }
+
+    #[test]
+    fn rejects_rates_too_slow_to_count() {
+        assert!(intervals(1e-300, 1, 1).is_none());
+        assert!(intervals(1.0, 1, u64::MAX).is_none());
+    }
+
+    #[test]
+    fn allows_a_full_burst_at_once() {
+        let (mut agent, start) = (RateLimitAgent::new(), Instant::now());
+        for _ in 0..3 {
+            assert!(allowed(acquire(&mut agent, 1.0, 3, 1, false, start)));
+        }
+        assert_eq!(retry_after(acquire(&mut agent, 1.0, 3, 1, false, start)), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn refills_one_token_per_interval() {
+        let (mut agent, start) = (RateLimitAgent::new(), Instant::now());
+        for _ in 0..3 {
+            acquire(&mut agent, 2.0, 3, 1, false, start);
+        }
+        let later = start + Duration::from_millis(500);
+        assert!(allowed(acquire(&mut agent, 2.0, 3, 1, false, later)));
+        assert_eq!(
+            retry_after(acquire(&mut agent, 2.0, 3, 1, false, later)),
+            Some(Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn refills_no_further_than_the_burst() {
+        let (mut agent, start) = (RateLimitAgent::new(), Instant::now());
+        acquire(&mut agent, 1.0, 2, 1, false, start);
+        let later = start + Duration::from_secs(100);
+        assert!(allowed(acquire(&mut agent, 1.0, 2, 1, false, later)));
+        assert!(allowed(acquire(&mut agent, 1.0, 2, 1, false, later)));
+        assert_eq!(retry_after(acquire(&mut agent, 1.0, 2, 1, false, later)), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn retries_after_enough_tokens_for_the_cost() {
+        let (mut agent, start) = (RateLimitAgent::new(), Instant::now());
+        assert!(allowed(acquire(&mut agent, 1.0, 3, 2, false, start)));
+        assert_eq!(retry_after(acquire(&mut agent, 1.0, 3, 2, false, start)), Some(Duration::from_secs(1)));
+        let later = start + Duration::from_secs(1);
+        assert!(allowed(acquire(&mut agent, 1.0, 3, 2, false, later)));
+    }
+
+    #[test]
+    fn takes_nothing_when_denied() {
+        let (mut agent, start) = (RateLimitAgent::new(), Instant::now());
+        acquire(&mut agent, 1.0, 1, 1, false, start);
+        for _ in 0..3 {
+            let acquired = acquire(&mut agent, 1.0, 1, 1, false, start);
+            assert_eq!(retry_after(acquired), Some(Duration::from_secs(1)));
+        }
+    }
+
+    #[test]
+    fn reserves_in_order_when_blocking() {
+        let (mut agent, start) = (RateLimitAgent::new(), Instant::now());
+        assert!(allowed(acquire(&mut agent, 2.0, 1, 1, true, start)));
+        assert_eq!(wait(acquire(&mut agent, 2.0, 1, 1, true, start)), Some(Duration::from_millis(500)));
+        assert_eq!(wait(acquire(&mut agent, 2.0, 1, 1, true, start)), Some(Duration::from_secs(1)));
+        assert_eq!(
+            retry_after(acquire(&mut agent, 2.0, 1, 1, false, start)),
+            Some(Duration::from_millis(1500))
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use std::cell::Cell;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::task::{Context, Poll, Waker};
+
+use mio::unix::EventedFd;
+use mio::{Evented, PollOpt, Ready, Token};
+use tokio::io::PollEvented;
+use zmq;
+
+// The descriptor libzmq signals a socket's events on.  libzmq owns it; this only watches it.
+struct Fd(RawFd);
+
+impl Evented for Fd {
+    fn register(&self, poll: &mio::Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.0).register(poll, token, interest, opts)
+    }
+
+    fn reregister(&self, poll: &mio::Poll, token: Token, interest: Ready, opts: PollOpt) -> io::Result<()> {
+        EventedFd(&self.0).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.0).deregister(poll)
+    }
+}
+
+/// Lets a task sleep until a zeromq socket has a message to read, or room to send one, rather than
+/// asking it over and over.  Has to be dropped before the socket is closed.
+pub struct Readiness {
+    evented: PollEvented<Fd>,
+    // The task waiting on the socket, to wake after a send.
+    waiting: Cell<Option<Waker>>,
+}
+
+impl Readiness {
+    pub fn new(socket: &zmq::Socket) -> io::Result<Readiness> {
+        let fd = socket.get_fd().map_err(io::Error::from)?;
+        Ok(Readiness {
+            evented: PollEvented::new(Fd(fd))?,
+            waiting: Cell::new(None),
+        })
+    }
+
+    /// Resolves once the socket has a message to read.
+    pub fn poll_readable(&self, socket: &zmq::Socket, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.poll_events(socket, zmq::POLLIN, cx)
+    }
+
+    /// Resolves once the socket has any of `events`: a message to read for POLLIN, room to send
+    /// one for POLLOUT.
+    ///
+    /// The descriptor only says when something changed, not what: libzmq signals it on edges, and
+    /// only ZMQ_EVENTS says whether there's a message.  It's looked at again after every signal
+    /// is cleared, so one arriving in between isn't missed.
+    pub fn poll_events(&self, socket: &zmq::Socket, events: zmq::PollEvents, cx: &mut Context) -> Poll<io::Result<()>> {
+        loop {
+            match socket.get_events() {
+                Ok(current) if current.intersects(events) => return Poll::Ready(Ok(())),
+                Ok(_) => (),
+                Err(error) => return Poll::Ready(Err(error.into())),
+            }
+            match self.evented.poll_read_ready(cx, Ready::readable()) {
+                Poll::Ready(Ok(_)) => {
+                    if let Err(error) = self.evented.clear_read_ready(cx, Ready::readable()) {
+                        return Poll::Ready(Err(error));
+                    }
+                }
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                Poll::Pending => {
+                    self.waiting.set(Some(cx.waker().clone()));
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+
+    /// Has the waiting task look at the socket again.  Call after every send on it: libzmq
+    /// handles whatever is pending on the socket while sending, including messages that came in,
+    /// and those won't signal the descriptor again.  Also call when what the task waits for
+    /// changes.
+    pub fn sent(&self) {
+        if let Some(waker) = self.waiting.take() {
+            waker.wake();
+        }
+    }
+}
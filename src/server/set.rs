@@ -1,47 +1,1620 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::ops::Bound;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use actix::{Actor, Context, Handler, Message};
+use actix::{Actor, Addr, Arbiter, AsyncContext, Context, Handler, MailboxError, Message, MessageResult};
+use rand::seq::{IteratorRandom, SliceRandom};
+use rand::{thread_rng, Rng};
+use tokio::sync::oneshot;
 
+use crate::server::glob;
+
+// What each collection and member costs in bookkeeping on top of the bytes it holds, roughly, so
+// that lots of tiny members still count for something against the memory limit.
+const COLLECTION_OVERHEAD: usize = 64;
+const MEMBER_OVERHEAD: usize = 32;
+
+// How often collections past their TTL are looked for and removed.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+fn collection_cost(name: &str) -> usize {
+    name.len() + COLLECTION_OVERHEAD
+}
+
+fn member_cost(kind: Kind, value: &[u8]) -> usize {
+    match kind {
+        Kind::Set => value.len() + MEMBER_OVERHEAD,
+        // The multiplicity takes another 8 bytes.
+        Kind::Multiset => value.len() + 8 + MEMBER_OVERHEAD,
+    }
+}
+
+// Sets and multisets share a keyspace, so a name can only ever refer to one kind of collection.
+enum Collection {
+    // Kept in order, so a scan can pick up after the last member it returned no matter what was
+    // inserted in the meantime.
+    Set(BTreeSet<Vec<u8>>),
+    // Each member maps to its multiplicity, which is never 0.
+    Multiset(HashMap<Vec<u8>, u64>),
+}
+
+impl Collection {
+    fn kind(&self) -> Kind {
+        match self {
+            Collection::Set(_) => Kind::Set,
+            Collection::Multiset(_) => Kind::Multiset,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Collection::Set(inner) => inner.len(),
+            Collection::Multiset(inner) => inner.len(),
+        }
+    }
+}
+
+/// What a capped set does about inserting a new member once it's full.
+#[derive(Clone, Copy)]
+pub enum Overflow {
+    /// Turn the insert down.
+    Reject,
+    /// Make room by removing the member inserted longest ago.
+    EvictOldest,
+    /// Make room by removing a member at random.
+    EvictRandom,
+}
+
+// A limit on how many members a set holds.
+struct Cap {
+    max_members: u64,
+    overflow: Overflow,
+    // The members in the order they were inserted, each under the value of `next` at the time, to
+    // find the oldest.
+    order: BTreeMap<u64, Vec<u8>>,
+    inserted: HashMap<Vec<u8>, u64>,
+    next: u64,
+}
+
+impl Cap {
+    fn new(max_members: u64, overflow: Overflow) -> Cap {
+        Cap {
+            max_members,
+            overflow,
+            order: BTreeMap::new(),
+            inserted: HashMap::new(),
+            next: 0,
+        }
+    }
+
+    fn add(&mut self, value: Vec<u8>) {
+        self.next += 1;
+        self.order.insert(self.next, value.clone());
+        self.inserted.insert(value, self.next);
+    }
+
+    fn forget(&mut self, value: &[u8]) {
+        if let Some(next) = self.inserted.remove(value) {
+            self.order.remove(&next);
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Set,
+    Multiset,
+}
+
+// One team's collections on one shard, kept apart from everyone else's so their names never
+// collide.
+struct Namespace {
+    name: String,
+    data: HashMap<String, Collection>,
+    // The value of `clock` when each collection last changed, for watches to compare against.  The
+    // clock never goes backwards, so a version is never reused even if a name is.
+    versions: HashMap<String, u64>,
+    clock: u64,
+    // The version of every collection that hasn't changed since the namespace was created: where
+    // its clock started, past every version handed out before it was last dropped, so a watch
+    // from then can't match a collection that has changed since.
+    floor: u64,
+    // Shared with the namespace on every other shard.
+    usage: Arc<Usage>,
+    // Members taken up front for a batch, for its inserts to use before taking any more.
+    credit: u64,
+    limits: Arc<Limits>,
+    // What each collection counts for against the memory limit, kept up to date as it changes
+    // rather than added up over its members.
+    sizes: HashMap<String, usize>,
+    // When each collection was last used, to find the least recently used one to evict.
+    used: HashMap<String, Instant>,
+    // When each collection with a TTL is to be removed.
+    expires: HashMap<String, Instant>,
+    // The limits on the sets created with one.
+    caps: HashMap<String, Cap>,
+}
+
+/// What to do when a write would take the collections past the memory limit.
+#[derive(Clone, Copy)]
+pub enum EvictionPolicy {
+    /// Turn the write down.
+    Reject,
+    /// Remove the least recently used collections until the write fits.
+    Lru,
+    /// Remove the collections with a TTL until the write fits, soonest to expire first, then the
+    /// least recently used of the rest.
+    TtlFirst,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(EvictionPolicy::Reject),
+            "lru" => Ok(EvictionPolicy::Lru),
+            "ttl-first" => Ok(EvictionPolicy::TtlFirst),
+            _ => Err(format!(
+                "unknown eviction policy {:?}; expected \"reject\", \"lru\" or \"ttl-first\"",
+                s
+            )),
+        }
+    }
+}
+
+// A namespace's members across every shard, counting each distinct multiset member once, and the
+// most it may hold.
+struct Usage {
+    members: AtomicU64,
+    // u64::MAX without a quota.
+    quota: AtomicU64,
+}
+
+impl Usage {
+    fn quota(&self) -> Option<u64> {
+        match self.quota.load(Ordering::SeqCst) {
+            u64::MAX => None,
+            quota => Some(quota),
+        }
+    }
+
+    // Takes up `new` more members if they fit in the quota, even with other shards taking some at
+    // the same time.
+    fn admit(&self, new: u64) -> Result<(), SetError> {
+        let mut members = self.members.load(Ordering::SeqCst);
+        loop {
+            if let Some(quota) = self.quota() {
+                if members + new > quota {
+                    return Err(SetError::QuotaExceeded { quota });
+                }
+            }
+            match self
+                .members
+                .compare_exchange_weak(members, members + new, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return Ok(()),
+                Err(current) => members = current,
+            }
+        }
+    }
+
+    fn release(&self, members: u64) {
+        self.members.fetch_sub(members, Ordering::SeqCst);
+    }
+}
+
+// Which of `count` shards holds the collection.
+fn shard_index(namespace: &str, name: &str, count: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    (namespace, name).hash(&mut hasher);
+    (hasher.finish() % count as u64) as usize
+}
+
+// What every shard shares: each namespace's usage, and what each shard's collections hold against
+// its share of the memory limit.
+struct Limits {
+    namespaces: Mutex<HashMap<String, Arc<Usage>>>,
+    // The most bytes the collections on each shard may count for, if limited.
+    max_memory: Option<usize>,
+    policy: EvictionPolicy,
+    // One per shard, counting a collection against the shard that holds it even while a request
+    // spanning shards has it taken out.
+    held: Vec<AtomicUsize>,
+}
+
+impl Limits {
+    // What the shard holding the collection holds.
+    fn held(&self, namespace: &str, name: &str) -> &AtomicUsize {
+        &self.held[shard_index(namespace, name, self.held.len())]
+    }
+
+    fn usage(&self, namespace: &str) -> Arc<Usage> {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        namespaces
+            .entry(namespace.to_owned())
+            .or_insert_with(|| {
+                Arc::new(Usage {
+                    members: AtomicU64::new(0),
+                    quota: AtomicU64::new(u64::MAX),
+                })
+            })
+            .clone()
+    }
+}
+
+/// One shard of the sets and multisets; see `SetShards`.
 pub struct SetAgent {
-    data: HashMap<String, HashSet<Vec<u8>>>,
+    namespaces: HashMap<String, Namespace>,
+    limits: Arc<Limits>,
+    // Which shard this is.
+    index: usize,
+    // Where the clock of a namespace created from now on starts, and the version of its
+    // collections until they change: past that of every namespace dropped, so no version is ever
+    // reused.
+    clock: u64,
 }
 
 impl SetAgent {
-    pub fn new() -> SetAgent {
+    fn new(limits: Arc<Limits>, index: usize) -> SetAgent {
         SetAgent {
+            namespaces: HashMap::new(),
+            limits,
+            index,
+            clock: 0,
+        }
+    }
+
+    // The namespace, created if this shard holds nothing in it yet.  Requests that only look at
+    // or remove collections get at `namespaces` directly instead, so they never create one.
+    fn namespace(&mut self, namespace: String) -> &mut Namespace {
+        let (limits, clock) = (&self.limits, self.clock);
+        self.namespaces.entry(namespace).or_insert_with_key(|namespace| {
+            let mut ns = Namespace::new(limits, namespace);
+            ns.clock = clock;
+            ns.floor = clock;
+            ns
+        })
+    }
+
+    // Marks the named collections in the namespace as just used, if they exist.
+    fn used(&mut self, namespace: &str, names: &[&str]) {
+        let now = Instant::now();
+        if let Some(ns) = self.namespaces.get_mut(namespace) {
+            for name in names {
+                if ns.data.contains_key(*name) {
+                    ns.used.insert(name.to_string(), now);
+                }
+            }
+        }
+    }
+
+    // Marks the named collections as just used, and makes sure a write adding `bytes` more to this
+    // shard will fit in its share of the memory limit, evicting other collections on it if the
+    // policy allows.  The named collections are never evicted, since the write is about to use
+    // them.
+    fn reserve(&mut self, namespace: &str, names: &[&str], bytes: usize) -> Result<(), SetError> {
+        // Including the ones the write is about to create.
+        if !names.is_empty() {
+            let now = Instant::now();
+            let ns = self.namespace(namespace.to_owned());
+            for name in names {
+                ns.used.insert(name.to_string(), now);
+            }
+        }
+        let max_memory = match self.limits.max_memory {
+            Some(max_memory) => max_memory,
+            None => return Ok(()),
+        };
+
+        while self.limits.held[self.index].load(Ordering::SeqCst) + bytes > max_memory {
+            let victim = match self.limits.policy {
+                EvictionPolicy::Reject => None,
+                EvictionPolicy::Lru => self.victim(namespace, names, false),
+                EvictionPolicy::TtlFirst => self
+                    .victim(namespace, names, true)
+                    .or_else(|| self.victim(namespace, names, false)),
+            };
+            let (victim_namespace, name) = match victim {
+                Some(victim) => victim,
+                None => return Err(SetError::OutOfMemory { max_memory }),
+            };
+            self.namespace(victim_namespace).remove_collection(&name);
+        }
+        Ok(())
+    }
+
+    // Runs a request on this shard, which holds every collection it touches.
+    fn apply<S: Spanning>(&mut self, request: S) -> Result<S::Output, SetError> {
+        let namespace = request.namespace().to_owned();
+        let bytes = self.namespace(namespace.clone()).growth(request.additions());
+        self.reserve(&namespace, &request.names(), bytes)?;
+        request.apply(self.namespace(namespace))
+    }
+
+    // Takes the named collections out of the namespace, along with its clock.
+    fn take_out(&mut self, namespace: String, names: Vec<String>) -> (u64, Vec<Taken>) {
+        match self.namespaces.get_mut(&namespace) {
+            Some(ns) => (ns.clock, names.into_iter().map(|name| ns.take_out(name)).collect()),
+            None => (self.clock, names.into_iter().map(|name| Taken::absent(name, self.clock)).collect()),
+        }
+    }
+
+    // Puts collections taken out back, moving the clock up to where the request that had them
+    // left it.
+    fn put_back(&mut self, namespace: String, clock: u64, taken: Vec<Taken>) {
+        let floor = self.clock;
+        if !self.namespaces.contains_key(&namespace) && taken.iter().all(|taken| taken.is_empty(floor)) {
+            return;
+        }
+        let ns = self.namespace(namespace);
+        ns.clock = ns.clock.max(clock);
+        for taken in taken {
+            ns.put_back(taken);
+        }
+    }
+
+    // The collection to evict next, other than the named ones in the namespace: the one expiring
+    // soonest with `by_ttl`, otherwise the one used longest ago.
+    fn victim(&self, namespace: &str, names: &[&str], by_ttl: bool) -> Option<(String, String)> {
+        self.namespaces
+            .iter()
+            .flat_map(|(ns_name, ns)| {
+                ns.data
+                    .keys()
+                    .filter(move |name| ns_name != namespace || !names.contains(&name.as_str()))
+                    .filter_map(move |name| {
+                        let when = if by_ttl {
+                            Some(*ns.expires.get(name)?)
+                        } else {
+                            ns.used.get(name).copied()
+                        };
+                        Some((when, ns_name, name))
+                    })
+            })
+            .min_by_key(|(when, _, _)| *when)
+            .map(|(_, ns_name, name)| (ns_name.clone(), name.clone()))
+    }
+
+    // Removes every collection past its TTL.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        for ns in self.namespaces.values_mut() {
+            let expired: Vec<String> = ns
+                .expires
+                .iter()
+                .filter(|(_, expires)| **expires <= now)
+                .map(|(name, _)| name.clone())
+                .collect();
+            for name in expired {
+                ns.remove_collection(&name);
+            }
+        }
+    }
+
+    // Drops the namespaces left without collections, and the usage of those without a quota once
+    // no shard has collections in them, so namespaces used once and never again don't pile up.
+    fn drop_empty(&mut self) {
+        let clock = &mut self.clock;
+        self.namespaces.retain(|_, ns| {
+            if ns.data.is_empty() && ns.credit == 0 {
+                *clock = (*clock).max(ns.clock);
+                return false;
+            }
+            true
+        });
+        let mut namespaces = self.limits.namespaces.lock().unwrap();
+        namespaces.retain(|_, usage| Arc::strong_count(usage) > 1 || usage.quota().is_some());
+    }
+}
+
+impl Namespace {
+    fn new(limits: &Arc<Limits>, namespace: &str) -> Namespace {
+        Namespace {
+            name: namespace.to_owned(),
             data: HashMap::new(),
+            versions: HashMap::new(),
+            clock: 0,
+            floor: 0,
+            usage: limits.usage(namespace),
+            credit: 0,
+            limits: limits.clone(),
+            sizes: HashMap::new(),
+            used: HashMap::new(),
+            expires: HashMap::new(),
+            caps: HashMap::new(),
+        }
+    }
+
+    // Takes up `new` more members for a batch up front, so it's turned down as a whole if they
+    // wouldn't all fit in the quota.  Whatever the batch doesn't use goes back with `settle`.
+    fn admit(&mut self, new: u64) -> Result<(), SetError> {
+        self.usage.admit(new)?;
+        self.credit += new;
+        Ok(())
+    }
+
+    fn settle(&mut self) {
+        self.usage.release(self.credit);
+        self.credit = 0;
+    }
+
+    // Takes up one more member, out of what the batch took up front if there's any left.
+    fn take(&mut self) -> Result<(), SetError> {
+        if self.credit > 0 {
+            self.credit -= 1;
+            return Ok(());
+        }
+        self.usage.admit(1)
+    }
+
+    fn grow(&mut self, name: &str, bytes: usize) {
+        match self.sizes.get_mut(name) {
+            Some(size) => *size += bytes,
+            None => {
+                self.sizes.insert(name.to_owned(), bytes);
+            }
+        }
+        self.limits.held(&self.name, name).fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    fn shrink(&mut self, name: &str, bytes: usize) {
+        if let Some(size) = self.sizes.get_mut(name) {
+            *size -= bytes;
+        }
+        self.limits.held(&self.name, name).fetch_sub(bytes, Ordering::SeqCst);
+    }
+
+    fn touch(&mut self, name: &str) {
+        self.clock += 1;
+        self.versions.insert(name.to_owned(), self.clock);
+    }
+
+    fn contains(&self, name: &str, value: &[u8]) -> bool {
+        match self.data.get(name) {
+            None => false,
+            Some(Collection::Set(inner)) => inner.contains(value),
+            Some(Collection::Multiset(inner)) => inner.contains_key(value),
+        }
+    }
+
+    fn check_kind(&self, name: &str, kind: Kind) -> Result<(), SetError> {
+        match self.data.get(name) {
+            Some(collection) if collection.kind() != kind => Err(SetError::WrongType {
+                name: name.to_owned(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn version(&self, name: &str) -> u64 {
+        self.versions.get(name).copied().unwrap_or(self.floor)
+    }
+
+    // The bytes inserting each of `members` would add, counting each new member and collection
+    // once.
+    fn growth<'a, I>(&self, members: I) -> usize
+    where
+        I: IntoIterator<Item = (&'a str, &'a [u8], Kind)>,
+    {
+        let mut collections = HashSet::new();
+        let mut new = HashSet::new();
+        let mut bytes = 0;
+        for (name, value, kind) in members {
+            if !self.data.contains_key(name) && collections.insert(name) {
+                bytes += collection_cost(name);
+            }
+            if !self.contains(name, value) && new.insert((name, value)) {
+                bytes += member_cost(kind, value);
+            }
+        }
+        bytes
+    }
+
+    // Removes a collection altogether, along with its TTL and cap.
+    fn remove_collection(&mut self, name: &str) {
+        if let Some(collection) = self.data.remove(name) {
+            self.usage.release(collection.len() as u64);
+            let bytes = self.sizes.remove(name).unwrap_or(0);
+            self.limits.held(&self.name, name).fetch_sub(bytes, Ordering::SeqCst);
+            self.touch(name);
+        }
+        self.used.remove(name);
+        self.expires.remove(name);
+        self.caps.remove(name);
+    }
+
+    // Takes a collection out along with everything kept about it, for a request spanning shards.
+    // It still counts against the memory limit while it's out.
+    fn take_out(&mut self, name: String) -> Taken {
+        let collection = self.data.remove(&name);
+        self.used.remove(&name);
+        Taken {
+            bytes: self.sizes.remove(&name).unwrap_or(0),
+            version: self.versions.remove(&name).unwrap_or(self.floor),
+            cap: self.caps.remove(&name),
+            expires: self.expires.remove(&name),
+            collection,
+            name,
+        }
+    }
+
+    fn put_back(&mut self, Taken { name, collection, bytes, version, cap, expires }: Taken) {
+        if let Some(collection) = collection {
+            self.sizes.insert(name.clone(), bytes);
+            self.used.insert(name.clone(), Instant::now());
+            self.data.insert(name.clone(), collection);
+        }
+        if version != self.floor {
+            self.versions.insert(name.clone(), version);
+        }
+        if let Some(cap) = cap {
+            self.caps.insert(name.clone(), cap);
+        }
+        if let Some(expires) = expires {
+            self.expires.insert(name, expires);
+        }
+    }
+
+    // How many members the namespace grows by when `new` members that aren't there yet are
+    // inserted into the collection, once a capped set has evicted what it has to, or an error if
+    // a capped set would turn them down.
+    fn room(&self, name: &str, new: u64) -> Result<u64, SetError> {
+        let cap = match self.caps.get(name) {
+            Some(cap) => cap,
+            None => return Ok(new),
+        };
+        let len = self.data.get(name).map_or(0, |collection| collection.len() as u64);
+        let free = cap.max_members.saturating_sub(len);
+        match cap.overflow {
+            Overflow::Reject if new > free => Err(SetError::Full {
+                name: name.to_owned(),
+                max_members: cap.max_members,
+            }),
+            _ => Ok(new.min(free)),
+        }
+    }
+
+    // Removes a member from a full capped set to make room for another, as its overflow policy
+    // says.
+    fn evict(&mut self, name: &str) {
+        let victim = match (self.caps.get(name), self.data.get(name)) {
+            (Some(cap), Some(Collection::Set(inner))) => match cap.overflow {
+                Overflow::Reject => None,
+                Overflow::EvictOldest => cap.order.values().next().cloned(),
+                Overflow::EvictRandom => inner.iter().choose(&mut thread_rng()).cloned(),
+            },
+            _ => None,
+        };
+        if let Some(victim) = victim {
+            let _ = self.remove(name.to_owned(), &victim);
+        }
+    }
+
+    fn insert(&mut self, name: String, value: Vec<u8>) -> Result<bool, SetError> {
+        self.check_kind(&name, Kind::Set)?;
+        if !self.contains(&name, &value) {
+            if self.room(&name, 1)? == 0 {
+                // Taking the evicted member's place, so the count stays where it was.
+                self.evict(&name);
+                self.usage.members.fetch_add(1, Ordering::SeqCst);
+            } else {
+                self.take()?;
+            }
+        }
+
+        let cost = member_cost(Kind::Set, &value);
+        // Capped sets keep track of the order their members were inserted in.
+        let added = self.caps.get(&name).map(|_| value.clone());
+        let inserted = match self.data.get_mut(&name) {
+            None => {
+                let mut inner = BTreeSet::new();
+                inner.insert(value);
+                let _ = self.data.insert(name.clone(), Collection::Set(inner));
+                self.grow(&name, collection_cost(&name));
+                true
+            }
+            Some(Collection::Set(inner)) => inner.insert(value),
+            Some(_) => return Err(SetError::WrongType { name }),
+        };
+        if inserted {
+            self.grow(&name, cost);
+            if let (Some(cap), Some(value)) = (self.caps.get_mut(&name), added) {
+                cap.add(value);
+            }
+            self.touch(&name);
+        }
+        Ok(inserted)
+    }
+
+    fn remove(&mut self, name: String, value: &[u8]) -> Result<bool, SetError> {
+        let removed = match self.data.get_mut(&name) {
+            None => false,
+            Some(Collection::Set(inner)) => inner.remove(value),
+            Some(_) => return Err(SetError::WrongType { name }),
+        };
+        if removed {
+            self.usage.release(1);
+            self.shrink(&name, member_cost(Kind::Set, value));
+            if let Some(cap) = self.caps.get_mut(&name) {
+                cap.forget(value);
+            }
+            self.touch(&name);
+        }
+        Ok(removed)
+    }
+
+    fn increment(&mut self, name: String, value: Vec<u8>) -> Result<u64, SetError> {
+        self.check_kind(&name, Kind::Multiset)?;
+        if !self.contains(&name, &value) {
+            self.take()?;
+        }
+
+        let cost = member_cost(Kind::Multiset, &value);
+        let count = match self.data.get_mut(&name) {
+            None => {
+                let mut inner = HashMap::new();
+                inner.insert(value, 1);
+                let _ = self.data.insert(name.clone(), Collection::Multiset(inner));
+                self.grow(&name, collection_cost(&name));
+                1
+            }
+            Some(Collection::Multiset(inner)) => {
+                let count = inner.entry(value).or_insert(0);
+                *count = count.saturating_add(1);
+                *count
+            }
+            Some(_) => return Err(SetError::WrongType { name }),
+        };
+        if count == 1 {
+            self.grow(&name, cost);
+        }
+        self.touch(&name);
+        Ok(count)
+    }
+}
+
+// A collection taken out of a shard, and everything kept about it.
+struct Taken {
+    name: String,
+    collection: Option<Collection>,
+    bytes: usize,
+    version: u64,
+    cap: Option<Cap>,
+    expires: Option<Instant>,
+}
+
+impl Taken {
+    // Nothing, out of a shard without the namespace, whose clock is at `floor`.
+    fn absent(name: String, floor: u64) -> Taken {
+        Taken {
+            name,
+            collection: None,
+            bytes: 0,
+            version: floor,
+            cap: None,
+            expires: None,
+        }
+    }
+
+    // Whether there was nothing to take, nor anything made since to put back, into a namespace
+    // that would start at `floor`.
+    fn is_empty(&self, floor: u64) -> bool {
+        self.collection.is_none() && self.version == floor && self.cap.is_none() && self.expires.is_none()
+    }
+}
+
+// A request on several collections, which may be held by different shards.
+trait Spanning {
+    type Output;
+
+    fn namespace(&self) -> &str;
+
+    // Every collection the request touches.
+    fn names(&self) -> Vec<&str>;
+
+    // The members the request may insert, to reserve memory for.
+    fn additions(&self) -> Vec<(&str, &[u8], Kind)>;
+
+    // Carries out the request on a namespace holding every collection it touches.
+    fn apply(self, ns: &mut Namespace) -> Result<Self::Output, SetError>;
+}
+
+type Job = Box<dyn FnOnce(&mut SetAgent) + Send>;
+
+// Has a shard run nothing but the jobs sent to it until they stop coming.
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Hold {
+    jobs: mpsc::Receiver<Job>,
+    held: oneshot::Sender<()>,
+}
+
+impl Handler<Hold> for SetAgent {
+    type Result = ();
+
+    fn handle(&mut self, Hold { jobs, held }: Hold, _ctx: &mut Context<Self>) -> Self::Result {
+        if held.send(()).is_err() {
+            return;
+        }
+        // Blocking the shard's thread, which is what keeps every other request out.
+        for job in jobs {
+            job(self);
+        }
+    }
+}
+
+// A shard that has been held, let go when this is dropped.
+struct Held(mpsc::Sender<Job>);
+
+impl Held {
+    async fn hold(shard: &Addr<SetAgent>) -> Result<Held, MailboxError> {
+        let (jobs, receiver) = mpsc::channel();
+        let (held, acknowledged) = oneshot::channel();
+        // Not waiting on the handler, since it only returns once the shard is let go.
+        shard.do_send(Hold { jobs: receiver, held });
+        acknowledged.await.map_err(|_| MailboxError::Closed)?;
+        Ok(Held(jobs))
+    }
+
+    async fn run<T, F>(&self, job: F) -> Result<T, MailboxError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut SetAgent) -> T + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        self.0
+            .send(Box::new(move |agent: &mut SetAgent| {
+                let _ = sender.send(job(agent));
+            }))
+            .map_err(|_| MailboxError::Closed)?;
+        receiver.await.map_err(|_| MailboxError::Closed)
+    }
+}
+
+type Reply<T> = Pin<Box<dyn Future<Output = Result<T, MailboxError>>>>;
+
+/// Sets and multisets, split between several agents by a hash of their namespace and name, each
+/// agent on its own thread.  A request on one collection goes straight to the agent holding it.  A
+/// request on several goes to one agent if it holds them all; otherwise the agents holding them
+/// are held in order, so two such requests never wait on each other, and their collections are
+/// taken out, worked on together and put back before they're let go.
+#[derive(Clone)]
+pub struct SetShards {
+    shards: Vec<Addr<SetAgent>>,
+    limits: Arc<Limits>,
+}
+
+impl SetShards {
+    /// Splits `max_memory` evenly between the shards, each evicting or turning writes down once
+    /// its own share is full, whatever the others hold.
+    pub fn start(count: usize, max_memory: Option<usize>, policy: EvictionPolicy) -> SetShards {
+        let count = count.max(1);
+        let limits = Arc::new(Limits {
+            namespaces: Mutex::new(HashMap::new()),
+            max_memory: max_memory.map(|max_memory| max_memory / count),
+            policy,
+            held: (0..count).map(|_| AtomicUsize::new(0)).collect(),
+        });
+        let shards = (0..count)
+            .map(|index| {
+                let limits = limits.clone();
+                SetAgent::start_in_arbiter(&Arbiter::new(), move |_ctx| SetAgent::new(limits, index))
+            })
+            .collect();
+        SetShards { shards, limits }
+    }
+
+    fn index(&self, namespace: &str, name: &str) -> usize {
+        shard_index(namespace, name, self.shards.len())
+    }
+
+    /// The agent holding the named collection.
+    pub fn shard(&self, namespace: &str, name: &str) -> &Addr<SetAgent> {
+        &self.shards[self.index(namespace, name)]
+    }
+
+    pub fn move_member(&self, request: Move) -> Reply<Result<bool, SetError>> {
+        self.span(request)
+    }
+
+    pub fn insert_unless(&self, request: InsertUnless) -> Reply<Result<bool, SetError>> {
+        self.span(request)
+    }
+
+    pub fn multi(&self, request: Multi) -> Reply<Result<Vec<OpResult>, SetError>> {
+        self.span(request)
+    }
+
+    /// Resolves to the names of the namespace's collections on every shard, in order.
+    pub fn list_names(&self, namespace: String, pattern: Option<Vec<u8>>) -> Reply<Vec<String>> {
+        let requests: Vec<_> = self
+            .shards
+            .iter()
+            .map(|shard| shard.send(ListNames { namespace: namespace.clone(), pattern: pattern.clone() }))
+            .collect();
+        Box::pin(async move {
+            let mut names = vec![];
+            for request in requests {
+                names.extend(request.await?);
+            }
+            names.sort();
+            Ok(names)
+        })
+    }
+
+    /// Removes every collection in the namespace from every shard, resolving to how many there
+    /// were.
+    pub fn flush(&self, namespace: String) -> Reply<u64> {
+        let requests: Vec<_> = self
+            .shards
+            .iter()
+            .map(|shard| shard.send(Flush { namespace: namespace.clone() }))
+            .collect();
+        Box::pin(async move {
+            let mut removed = 0;
+            for request in requests {
+                removed += request.await?;
+            }
+            Ok(removed)
+        })
+    }
+
+    /// Limits how many members the namespace can hold, or lifts the limit, returning how many it
+    /// holds now.  A namespace already over a new quota keeps its members, but takes no new ones
+    /// until enough are removed.
+    pub fn set_quota(&self, namespace: &str, quota: Option<u64>) -> u64 {
+        let usage = self.limits.usage(namespace);
+        usage.quota.store(quota.unwrap_or(u64::MAX), Ordering::SeqCst);
+        usage.members.load(Ordering::SeqCst)
+    }
+
+    fn span<S>(&self, request: S) -> Reply<Result<S::Output, SetError>>
+    where
+        S: Spanning + Message<Result = Result<<S as Spanning>::Output, SetError>> + Send + 'static,
+        S::Output: Send + 'static,
+        SetAgent: Handler<S>,
+    {
+        let mut names: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+        for name in request.names() {
+            names
+                .entry(self.index(request.namespace(), name))
+                .or_default()
+                .push(name.to_owned());
+        }
+        if names.len() <= 1 {
+            let index = names.keys().next().copied().unwrap_or(0);
+            return Box::pin(self.shards[index].send(request));
+        }
+        // Spawned rather than returned, so that nothing dropping the reply, like the messenger
+        // restarting, can stop it while it has collections taken out.
+        let (sender, receiver) = oneshot::channel();
+        let shards = self.clone();
+        actix_rt::spawn(async move {
+            let _ = sender.send(shards.across(names, request).await);
+        });
+        Box::pin(async move { receiver.await.unwrap_or(Err(MailboxError::Closed)) })
+    }
+
+    async fn across<S: Spanning>(
+        self,
+        names: BTreeMap<usize, Vec<String>>,
+        request: S,
+    ) -> Result<Result<S::Output, SetError>, MailboxError> {
+        let namespace = request.namespace().to_owned();
+        let mut spanned = Spanned {
+            ns: Namespace::new(&self.limits, &namespace),
+            namespace,
+            held: Vec::with_capacity(names.len()),
+        };
+        for (index, mut names) in names {
+            names.sort();
+            names.dedup();
+            spanned.held.push((Held::hold(&self.shards[index]).await?, names));
+        }
+
+        for (shard, names) in &spanned.held {
+            let (namespace, names) = (spanned.namespace.clone(), names.clone());
+            let (clock, taken) = shard.run(move |agent| agent.take_out(namespace, names)).await?;
+            spanned.ns.clock = spanned.ns.clock.max(clock);
+            for taken in taken {
+                spanned.ns.put_back(taken);
+            }
+        }
+
+        // Making room on each held shard for what its own collections grow by.
+        let additions = request.additions();
+        for (shard, names) in &spanned.held {
+            let bytes = spanned.ns.growth(
+                additions
+                    .iter()
+                    .copied()
+                    .filter(|(name, _, _)| names.iter().any(|held| held == name)),
+            );
+            let namespace = spanned.namespace.clone();
+            if let Err(error) = shard.run(move |agent| agent.reserve(&namespace, &[], bytes)).await? {
+                return Ok(Err(error));
+            }
+        }
+        Ok(request.apply(&mut spanned.ns))
+    }
+}
+
+// The shards a request spanning them holds, and the collections it took out of them, which are put
+// back when this is dropped, however the request ends.
+struct Spanned {
+    namespace: String,
+    held: Vec<(Held, Vec<String>)>,
+    ns: Namespace,
+}
+
+impl Drop for Spanned {
+    fn drop(&mut self) {
+        for (shard, names) in mem::take(&mut self.held) {
+            let taken: Vec<Taken> = names.iter().map(|name| self.ns.take_out(name.clone())).collect();
+            let (namespace, clock) = (self.namespace.clone(), self.ns.clock);
+            // Not waiting for it to run: the shard runs it before anything else, once let go.
+            let _ = shard.0.send(Box::new(move |agent: &mut SetAgent| agent.put_back(namespace, clock, taken)));
         }
     }
 }
 
 impl Actor for SetAgent {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(EXPIRY_INTERVAL, |act, _ctx| {
+            act.expire();
+            act.drop_empty();
+        });
+    }
+}
+
+pub enum SetError {
+    // The name refers to a different kind of collection than the operation works on.
+    WrongType { name: String },
+    // A watched collection changed since the version the transaction was prepared against.
+    Conflict { name: String },
+    // The operation would put the namespace over its quota.
+    QuotaExceeded { quota: u64 },
+    // The operation would take a capped set that turns down inserts past its limit.
+    Full { name: String, max_members: u64 },
+    // The operation would take a shard's collections past its share of the memory limit, and
+    // nothing could be evicted to make room.
+    OutOfMemory { max_memory: usize },
 }
 
 #[derive(Message)]
-#[rtype(result = "bool")]
+#[rtype(result = "Result<bool, SetError>")]
 pub struct Insert {
-    pub id: u32,
+    pub namespace: String,
     pub name: String,
     pub value: Vec<u8>,
 }
 
 impl Handler<Insert> for SetAgent {
-    type Result = bool;
+    type Result = Result<bool, SetError>;
+
+    fn handle(&mut self, Insert { namespace, name, value }: Insert, _ctx: &mut Context<Self>) -> Self::Result {
+        let bytes = self.namespace(namespace.clone()).growth(vec![(name.as_str(), value.as_slice(), Kind::Set)]);
+        self.reserve(&namespace, &[&name], bytes)?;
+        let ns = self.namespace(namespace);
+        ns.insert(name, value)
+    }
+}
+
+/// Inserts every value in order, resolving to whether each one was not already a member.
+#[derive(Message)]
+#[rtype(result = "Result<Vec<bool>, SetError>")]
+pub struct InsertMany {
+    pub namespace: String,
+    pub name: String,
+    pub values: Vec<Vec<u8>>,
+}
+
+impl Handler<InsertMany> for SetAgent {
+    type Result = MessageResult<InsertMany>;
+
+    fn handle(&mut self, InsertMany { namespace, name, values }: InsertMany, _ctx: &mut Context<Self>) -> Self::Result {
+        let bytes = self
+            .namespace(namespace.clone())
+            .growth(values.iter().map(|value| (name.as_str(), value.as_slice(), Kind::Set)));
+        if let Err(error) = self.reserve(&namespace, &[&name], bytes) {
+            return MessageResult(Err(error));
+        }
+        let ns = self.namespace(namespace);
+        if let Err(error) = ns.check_kind(&name, Kind::Set) {
+            return MessageResult(Err(error));
+        }
+        // All or nothing: the batch is turned down if its new values wouldn't all fit.
+        let new: HashSet<&Vec<u8>> = values.iter().filter(|value| !ns.contains(&name, value)).collect();
+        if let Err(error) = ns.room(&name, new.len() as u64).and_then(|growth| ns.admit(growth)) {
+            return MessageResult(Err(error));
+        }
+
+        let inserted = values.into_iter().map(|value| ns.insert(name.clone(), value)).collect();
+        ns.settle();
+        MessageResult(inserted)
+    }
+}
+
+/// Adds one to the multiplicity of the value, resolving to the new multiplicity.
+#[derive(Message)]
+#[rtype(result = "Result<u64, SetError>")]
+pub struct MultisetInsert {
+    pub namespace: String,
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+impl Handler<MultisetInsert> for SetAgent {
+    type Result = Result<u64, SetError>;
 
     fn handle(
         &mut self,
-        Insert { id: _, name, value }: Insert,
+        MultisetInsert { namespace, name, value }: MultisetInsert,
         _ctx: &mut Context<Self>,
     ) -> Self::Result {
-        match self.data.get_mut(&name) {
-            None => {
-                let mut inner = HashSet::new();
-                inner.insert(value);
-                let _ = self.data.insert(name, inner);
-                true
+        let bytes = self
+            .namespace(namespace.clone())
+            .growth(vec![(name.as_str(), value.as_slice(), Kind::Multiset)]);
+        self.reserve(&namespace, &[&name], bytes)?;
+        let ns = self.namespace(namespace);
+        ns.increment(name, value)
+    }
+}
+
+/// Resolves to every member of the multiset along with its multiplicity.
+#[derive(Message)]
+#[rtype(result = "Result<Vec<(Vec<u8>, u64)>, SetError>")]
+pub struct MultisetMembers {
+    pub namespace: String,
+    pub name: String,
+}
+
+impl Handler<MultisetMembers> for SetAgent {
+    type Result = MessageResult<MultisetMembers>;
+
+    fn handle(
+        &mut self,
+        MultisetMembers { namespace, name }: MultisetMembers,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        self.used(&namespace, &[&name]);
+        MessageResult(match self.namespaces.get(&namespace).and_then(|ns| ns.data.get(&name)) {
+            None => Ok(vec![]),
+            Some(Collection::Multiset(inner)) => Ok(inner
+                .iter()
+                .map(|(value, count)| (value.clone(), *count))
+                .collect()),
+            Some(_) => Err(SetError::WrongType { name }),
+        })
+    }
+}
+
+pub enum Op {
+    Insert { name: String, value: Vec<u8> },
+    Remove { name: String, value: Vec<u8> },
+    Increment { name: String, value: Vec<u8> },
+    // Reads the collection's version, to watch in a later transaction.
+    Version { name: String },
+}
+
+pub enum OpResult {
+    Inserted(bool),
+    Removed(bool),
+    Count(u64),
+    Version(u64),
+}
+
+/// Runs every operation in order, with nothing else running in between, resolving to each one's
+/// result.  Either all of them happen or none do: the transaction is turned down as a whole if
+/// any operation would hit a collection of the wrong type, or if any watched collection's version
+/// differs from the one given.  A collection that has never changed is at version 0, unless its
+/// namespace was left empty and dropped since, so the version is best read with `Op::Version`.
+#[derive(Message)]
+#[rtype(result = "Result<Vec<OpResult>, SetError>")]
+pub struct Multi {
+    pub namespace: String,
+    pub ops: Vec<Op>,
+    pub watches: Vec<(String, u64)>,
+}
+
+impl Handler<Multi> for SetAgent {
+    type Result = MessageResult<Multi>;
+
+    fn handle(&mut self, multi: Multi, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.apply(multi))
+    }
+}
+
+impl Spanning for Multi {
+    type Output = Vec<OpResult>;
+
+    fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    fn names(&self) -> Vec<&str> {
+        self.ops
+            .iter()
+            .map(|op| match op {
+                Op::Insert { name, .. }
+                | Op::Remove { name, .. }
+                | Op::Increment { name, .. }
+                | Op::Version { name } => name.as_str(),
+            })
+            .chain(self.watches.iter().map(|(name, _)| name.as_str()))
+            .collect()
+    }
+
+    fn additions(&self) -> Vec<(&str, &[u8], Kind)> {
+        self.ops
+            .iter()
+            .filter_map(|op| match op {
+                Op::Insert { name, value } => Some((name.as_str(), value.as_slice(), Kind::Set)),
+                Op::Increment { name, value } => Some((name.as_str(), value.as_slice(), Kind::Multiset)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn apply(self, ns: &mut Namespace) -> Result<Vec<OpResult>, SetError> {
+        let Multi { namespace: _, ops, watches } = self;
+        for (name, version) in watches {
+            if ns.version(&name) != version {
+                return Err(SetError::Conflict { name });
+            }
+        }
+
+        // Check every operation up front, keeping track of the collections created along the way,
+        // so that nothing is applied if anything would fail.
+        let mut created: HashMap<&str, Kind> = HashMap::new();
+        for op in &ops {
+            let (name, kind, creates) = match op {
+                Op::Insert { name, .. } => (name, Kind::Set, true),
+                Op::Remove { name, .. } => (name, Kind::Set, false),
+                Op::Increment { name, .. } => (name, Kind::Multiset, true),
+                Op::Version { .. } => continue,
+            };
+            let existing = ns
+                .data
+                .get(name)
+                .map(Collection::kind)
+                .or_else(|| created.get(name.as_str()).copied());
+            match existing {
+                Some(existing) if existing != kind => return Err(SetError::WrongType { name: name.clone() }),
+                None if creates => {
+                    created.insert(name, kind);
+                }
+                _ => (),
             }
-            Some(inner) => inner.insert(value),
         }
+
+        // Counting every member that isn't there yet, even if the transaction removes others
+        // first, so this errs on the side of turning transactions down.
+        let new: HashSet<(&str, &[u8])> = ops
+            .iter()
+            .filter_map(|op| match op {
+                Op::Insert { name, value } | Op::Increment { name, value } => {
+                    Some((name.as_str(), value.as_slice()))
+                }
+                _ => None,
+            })
+            .filter(|(name, value)| !ns.contains(name, value))
+            .collect();
+        let mut new_per_name: HashMap<&str, u64> = HashMap::new();
+        for (name, _) in new {
+            *new_per_name.entry(name).or_default() += 1;
+        }
+        let mut growth = 0;
+        for (name, new) in new_per_name {
+            growth += ns.room(name, new)?;
+        }
+        ns.admit(growth)?;
+
+        let results = ops
+            .into_iter()
+            .map(|op| match op {
+                Op::Insert { name, value } => ns.insert(name, value).map(OpResult::Inserted),
+                Op::Remove { name, value } => ns.remove(name, &value).map(OpResult::Removed),
+                Op::Increment { name, value } => ns.increment(name, value).map(OpResult::Count),
+                Op::Version { name } => Ok(OpResult::Version(ns.version(&name))),
+            })
+            .collect();
+        ns.settle();
+        results
+    }
+}
+
+/// Moves the value from one set to another in one step, so it is never in both or neither.
+/// Resolves to false, changing nothing, if the value wasn't in the source set.
+#[derive(Message)]
+#[rtype(result = "Result<bool, SetError>")]
+pub struct Move {
+    pub namespace: String,
+    pub source: String,
+    pub destination: String,
+    pub value: Vec<u8>,
+}
+
+impl Handler<Move> for SetAgent {
+    type Result = Result<bool, SetError>;
+
+    fn handle(&mut self, request: Move, _ctx: &mut Context<Self>) -> Self::Result {
+        self.apply(request)
+    }
+}
+
+impl Spanning for Move {
+    type Output = bool;
+
+    fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    fn names(&self) -> Vec<&str> {
+        vec![&self.source, &self.destination]
+    }
+
+    // Going by the destination alone, though the source shrinks by as much.
+    fn additions(&self) -> Vec<(&str, &[u8], Kind)> {
+        vec![(&self.destination, &self.value, Kind::Set)]
+    }
+
+    fn apply(self, ns: &mut Namespace) -> Result<bool, SetError> {
+        let Move { namespace: _, source, destination, value } = self;
+        ns.check_kind(&source, Kind::Set)?;
+        ns.check_kind(&destination, Kind::Set)?;
+        if source == destination {
+            return Ok(ns.contains(&source, &value));
+        }
+        // Otherwise a full destination could turn the value down after it's left the source.
+        if !ns.contains(&destination, &value) {
+            ns.room(&destination, 1)?;
+        }
+
+        if !ns.remove(source, &value)? {
+            return Ok(false);
+        }
+        ns.insert(destination, value)?;
+        Ok(true)
+    }
+}
+
+/// Inserts the value into a set unless it's already in any of the other named sets or multisets.
+/// Resolves to whether it was inserted.
+#[derive(Message)]
+#[rtype(result = "Result<bool, SetError>")]
+pub struct InsertUnless {
+    pub namespace: String,
+    pub name: String,
+    pub value: Vec<u8>,
+    pub unless_in: Vec<String>,
+}
+
+impl Handler<InsertUnless> for SetAgent {
+    type Result = Result<bool, SetError>;
+
+    fn handle(&mut self, request: InsertUnless, _ctx: &mut Context<Self>) -> Self::Result {
+        self.apply(request)
+    }
+}
+
+impl Spanning for InsertUnless {
+    type Output = bool;
+
+    fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    fn names(&self) -> Vec<&str> {
+        let mut names = vec![self.name.as_str()];
+        names.extend(self.unless_in.iter().map(String::as_str));
+        names
+    }
+
+    fn additions(&self) -> Vec<(&str, &[u8], Kind)> {
+        vec![(&self.name, &self.value, Kind::Set)]
+    }
+
+    fn apply(self, ns: &mut Namespace) -> Result<bool, SetError> {
+        let InsertUnless { namespace: _, name, value, unless_in } = self;
+        ns.check_kind(&name, Kind::Set)?;
+        if unless_in.iter().any(|other| ns.contains(other, &value)) {
+            return Ok(false);
+        }
+        ns.insert(name, value)
+    }
+}
+
+/// Pages through a set's members in byte order.  Looks at up to `count` members after `after`
+/// (or from the start), starting with `prefix` and matching the glob `pattern` if given, and
+/// resolves to the ones that match along with the member to continue after, if there are more.
+/// Every member that is in the set for the whole scan is returned exactly once.
+#[derive(Message)]
+#[rtype(result = "Result<(Vec<Vec<u8>>, Option<Vec<u8>>), SetError>")]
+pub struct Scan {
+    pub namespace: String,
+    pub name: String,
+    pub after: Option<Vec<u8>>,
+    pub count: usize,
+    pub prefix: Vec<u8>,
+    pub pattern: Option<Vec<u8>>,
+}
+
+impl Handler<Scan> for SetAgent {
+    type Result = MessageResult<Scan>;
+
+    fn handle(
+        &mut self,
+        Scan { namespace, name, after, count, prefix, pattern }: Scan,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        self.used(&namespace, &[&name]);
+        let inner = match self.namespaces.get(&namespace).and_then(|ns| ns.data.get(&name)) {
+            None => return MessageResult(Ok((vec![], None))),
+            Some(Collection::Set(inner)) => inner,
+            Some(_) => return MessageResult(Err(SetError::WrongType { name })),
+        };
+
+        // Members with the prefix are all in one run, so skip straight to it.
+        let start = match &after {
+            Some(after) if after.as_slice() >= prefix.as_slice() => Bound::Excluded(after.as_slice()),
+            _ => Bound::Included(prefix.as_slice()),
+        };
+
+        let mut members = vec![];
+        let mut last = None;
+        for (examined, member) in inner.range::<[u8], _>((start, Bound::Unbounded)).enumerate() {
+            if !member.starts_with(&prefix) {
+                break;
+            }
+            if examined == count {
+                return MessageResult(Ok((members, last)));
+            }
+            last = Some(member.clone());
+
+            let matched = match &pattern {
+                None => true,
+                Some(pattern) => glob::matches(pattern, member),
+            };
+            if matched {
+                members.push(member.clone());
+            }
+        }
+        MessageResult(Ok((members, None)))
+    }
+}
+
+/// Picks up to `count` random members of a set, resolving to them in random order.  Without
+/// `allow_duplicates`, no member is picked twice, so fewer come back if the set is smaller than
+/// `count`; with it, every pick is independent and exactly `count` come back unless the set is
+/// empty.
+#[derive(Message)]
+#[rtype(result = "Result<Vec<Vec<u8>>, SetError>")]
+pub struct RandMember {
+    pub namespace: String,
+    pub name: String,
+    pub count: usize,
+    pub allow_duplicates: bool,
+}
+
+impl Handler<RandMember> for SetAgent {
+    type Result = MessageResult<RandMember>;
+
+    fn handle(
+        &mut self,
+        RandMember { namespace, name, count, allow_duplicates }: RandMember,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        self.used(&namespace, &[&name]);
+        let inner = match self.namespaces.get(&namespace).and_then(|ns| ns.data.get(&name)) {
+            None => return MessageResult(Ok(vec![])),
+            Some(Collection::Set(inner)) => inner,
+            Some(_) => return MessageResult(Err(SetError::WrongType { name })),
+        };
+
+        let mut rng = thread_rng();
+        let mut members: Vec<Vec<u8>> = if !allow_duplicates {
+            // Never asking for room for more members than there are.
+            let picked = inner.iter().choose_multiple(&mut rng, count.min(inner.len()));
+            picked.into_iter().cloned().collect()
+        } else if inner.is_empty() {
+            vec![]
+        } else {
+            // Sets don't index, so pick the positions up front and collect them in one pass.
+            let len = inner.len() as u64;
+            let mut positions: Vec<usize> = (0..count)
+                .map(|_| (rng.gen::<u64>() % len) as usize)
+                .collect();
+            positions.sort_unstable();
+
+            let mut members = Vec::with_capacity(count);
+            let mut positions = positions.into_iter().peekable();
+            for (i, member) in inner.iter().enumerate() {
+                while positions.peek() == Some(&i) {
+                    positions.next();
+                    members.push(member.clone());
+                }
+            }
+            members
+        };
+        members.shuffle(&mut rng);
+        MessageResult(Ok(members))
+    }
+}
+
+/// Removes up to `count` random members from a set, resolving to the ones removed.
+#[derive(Message)]
+#[rtype(result = "Result<Vec<Vec<u8>>, SetError>")]
+pub struct Pop {
+    pub namespace: String,
+    pub name: String,
+    pub count: usize,
+}
+
+impl Handler<Pop> for SetAgent {
+    type Result = MessageResult<Pop>;
+
+    fn handle(&mut self, Pop { namespace, name, count }: Pop, _ctx: &mut Context<Self>) -> Self::Result {
+        self.used(&namespace, &[&name]);
+        let ns = match self.namespaces.get_mut(&namespace) {
+            Some(ns) => ns,
+            None => return MessageResult(Ok(vec![])),
+        };
+        let inner = match ns.data.get_mut(&name) {
+            None => return MessageResult(Ok(vec![])),
+            Some(Collection::Set(inner)) => inner,
+            Some(_) => return MessageResult(Err(SetError::WrongType { name })),
+        };
+
+        let mut rng = thread_rng();
+        let picked = inner.iter().choose_multiple(&mut rng, count.min(inner.len()));
+        let mut members: Vec<Vec<u8>> = picked.into_iter().cloned().collect();
+        members.shuffle(&mut rng);
+        for member in &members {
+            inner.remove(member);
+        }
+        if let Some(cap) = ns.caps.get_mut(&name) {
+            for member in &members {
+                cap.forget(member);
+            }
+        }
+        if !members.is_empty() {
+            ns.usage.release(members.len() as u64);
+            ns.shrink(&name, members.iter().map(|member| member_cost(Kind::Set, member)).sum());
+            ns.touch(&name);
+        }
+        MessageResult(Ok(members))
+    }
+}
+
+/// Resolves to the names of the namespace's collections in order, only those matching the glob
+/// `pattern` if given.
+#[derive(Message)]
+#[rtype(result = "Vec<String>")]
+pub struct ListNames {
+    pub namespace: String,
+    pub pattern: Option<Vec<u8>>,
+}
+
+impl Handler<ListNames> for SetAgent {
+    type Result = MessageResult<ListNames>;
+
+    fn handle(&mut self, ListNames { namespace, pattern }: ListNames, _ctx: &mut Context<Self>) -> Self::Result {
+        let ns = match self.namespaces.get(&namespace) {
+            Some(ns) => ns,
+            None => return MessageResult(vec![]),
+        };
+        let mut names: Vec<String> = ns
+            .data
+            .keys()
+            .filter(|name| match &pattern {
+                None => true,
+                Some(pattern) => glob::matches(pattern, name.as_bytes()),
+            })
+            .cloned()
+            .collect();
+        names.sort();
+        MessageResult(names)
+    }
+}
+
+/// Removes every collection in the namespace, resolving to how many there were.  The quota is
+/// kept, but TTLs go with their collections.
+#[derive(Message)]
+#[rtype(result = "u64")]
+pub struct Flush {
+    pub namespace: String,
+}
+
+impl Handler<Flush> for SetAgent {
+    type Result = u64;
+
+    fn handle(&mut self, Flush { namespace }: Flush, _ctx: &mut Context<Self>) -> Self::Result {
+        let ns = match self.namespaces.get_mut(&namespace) {
+            Some(ns) => ns,
+            None => return 0,
+        };
+        let names: Vec<String> = ns.data.keys().cloned().collect();
+        for name in &names {
+            ns.remove_collection(name);
+        }
+        names.len() as u64
+    }
+}
+
+/// Removes the collection once `ttl` has passed, or keeps it indefinitely again without one,
+/// resolving to false if there is no such collection.  Collections are removed within a second of
+/// expiring.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct Expire {
+    pub namespace: String,
+    pub name: String,
+    pub ttl: Option<Duration>,
+}
+
+impl Handler<Expire> for SetAgent {
+    type Result = bool;
+
+    fn handle(&mut self, Expire { namespace, name, ttl }: Expire, _ctx: &mut Context<Self>) -> Self::Result {
+        let ns = match self.namespaces.get_mut(&namespace) {
+            Some(ns) if ns.data.contains_key(&name) => ns,
+            _ => return false,
+        };
+        match ttl {
+            Some(ttl) => ns.expires.insert(name, Instant::now() + ttl),
+            None => ns.expires.remove(&name),
+        };
+        true
+    }
+}
+
+/// Creates an empty set holding at most `max_members` (which must be at least 1), doing as
+/// `overflow` says about inserts past that.  Resolves to false, changing nothing, if there is
+/// already a set by that name.
+#[derive(Message)]
+#[rtype(result = "Result<bool, SetError>")]
+pub struct Create {
+    pub namespace: String,
+    pub name: String,
+    pub max_members: u64,
+    pub overflow: Overflow,
+}
+
+impl Handler<Create> for SetAgent {
+    type Result = Result<bool, SetError>;
+
+    fn handle(
+        &mut self,
+        Create { namespace, name, max_members, overflow }: Create,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        let bytes = match self.namespace(namespace.clone()).data.get(&name) {
+            Some(_) => 0,
+            None => collection_cost(&name),
+        };
+        self.reserve(&namespace, &[&name], bytes)?;
+        let ns = self.namespace(namespace);
+        ns.check_kind(&name, Kind::Set)?;
+        if ns.data.contains_key(&name) {
+            return Ok(false);
+        }
+
+        ns.data.insert(name.clone(), Collection::Set(BTreeSet::new()));
+        ns.grow(&name, bytes);
+        ns.caps.insert(name.clone(), Cap::new(max_members, overflow));
+        ns.touch(&name);
+        Ok(true)
     }
 }
@@ -0,0 +1,328 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+use actix::{Actor, Context, Handler, Message, MessageResult};
+
+// Used when a client increments a sketch that doesn't exist yet.  Overestimates are bounded by
+// e/width of the total count with probability 1 - 1/e^depth; 2048x5 keeps that to about 0.13% of
+// the total, 99% of the time, in 80KiB.
+const DEFAULT_WIDTH: usize = 2048;
+const DEFAULT_DEPTH: usize = 5;
+
+// Used when a client adds to a top-K list without saying how many members to keep.
+const DEFAULT_K: u32 = 10;
+
+/// A count-min sketch.  Estimates how many times a value was counted in constant memory; the
+/// estimate is never lower than the real count, and only higher when values collide in every row.
+pub struct CountMinSketch {
+    counters: Vec<u64>,
+    width: usize,
+    depth: usize,
+}
+
+impl CountMinSketch {
+    pub fn new(width: usize, depth: usize) -> CountMinSketch {
+        let width = width.max(1);
+        let depth = depth.max(1);
+
+        CountMinSketch {
+            counters: vec![0; width * depth],
+            width,
+            depth,
+        }
+    }
+
+    // One counter per row, each row hashed independently.
+    fn indexes(&self, value: &[u8]) -> Vec<usize> {
+        (0..self.depth)
+            .map(|row| {
+                let mut hasher = DefaultHasher::new();
+                (row, value).hash(&mut hasher);
+                row * self.width + (hasher.finish() % self.width as u64) as usize
+            })
+            .collect()
+    }
+
+    /// Counts the value `increment` more times, returning the new estimate.
+    pub fn incr(&mut self, value: &[u8], increment: u64) -> u64 {
+        let mut estimate = u64::MAX;
+        for idx in self.indexes(value) {
+            self.counters[idx] = self.counters[idx].saturating_add(increment);
+            estimate = estimate.min(self.counters[idx]);
+        }
+        estimate
+    }
+
+    pub fn query(&self, value: &[u8]) -> u64 {
+        self.indexes(value)
+            .into_iter()
+            .map(|idx| self.counters[idx])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+/// The k values with the highest estimated counts seen so far.  Only the candidates are stored;
+/// their counts come from the count-min sketch of the same name.
+pub struct TopK {
+    k: usize,
+    members: HashMap<Vec<u8>, u64>,
+}
+
+impl TopK {
+    pub fn new(k: u32) -> TopK {
+        let k = if k == 0 { DEFAULT_K } else { k } as usize;
+
+        TopK {
+            k,
+            members: HashMap::with_capacity(k + 1),
+        }
+    }
+
+    // Records the latest estimate for the value, displacing the least frequent member if the
+    // value now outranks it.
+    fn update(&mut self, value: &[u8], estimate: u64) {
+        if let Some(count) = self.members.get_mut(value) {
+            *count = estimate;
+            return;
+        }
+
+        if self.members.len() < self.k {
+            self.members.insert(value.to_vec(), estimate);
+            return;
+        }
+
+        let (min_value, min_count) = match self.members.iter().min_by_key(|(_, count)| **count) {
+            Some((min_value, min_count)) => (min_value.clone(), *min_count),
+            None => return,
+        };
+
+        if estimate > min_count {
+            self.members.remove(&min_value);
+            self.members.insert(value.to_vec(), estimate);
+        }
+    }
+
+    /// The members, most frequent first.
+    pub fn list(&self) -> Vec<(Vec<u8>, u64)> {
+        let mut members: Vec<(Vec<u8>, u64)> = self
+            .members
+            .iter()
+            .map(|(value, count)| (value.clone(), *count))
+            .collect();
+        members.sort_by(|(a_value, a_count), (b_value, b_count)| {
+            b_count.cmp(a_count).then_with(|| a_value.cmp(b_value))
+        });
+        members
+    }
+}
+
+pub struct SketchAgent {
+    sketches: HashMap<String, CountMinSketch>,
+    top_k: HashMap<String, TopK>,
+}
+
+impl SketchAgent {
+    pub fn new() -> SketchAgent {
+        SketchAgent {
+            sketches: HashMap::new(),
+            top_k: HashMap::new(),
+        }
+    }
+
+    fn incr(&mut self, name: String, value: &[u8], increment: u64) -> u64 {
+        self.sketches
+            .entry(name)
+            .or_insert_with(|| CountMinSketch::new(DEFAULT_WIDTH, DEFAULT_DEPTH))
+            .incr(value, increment)
+    }
+}
+
+impl Actor for SketchAgent {
+    type Context = Context<Self>;
+}
+
+/// Resolves to the new estimated count for the value.
+#[derive(Message)]
+#[rtype(result = "u64")]
+pub struct Incr {
+    pub name: String,
+    pub value: Vec<u8>,
+    pub increment: u64,
+}
+
+impl Handler<Incr> for SketchAgent {
+    type Result = u64;
+
+    fn handle(
+        &mut self,
+        Incr { name, value, increment }: Incr,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        let estimate = self.incr(name.clone(), &value, increment);
+        if let Some(top_k) = self.top_k.get_mut(&name) {
+            top_k.update(&value, estimate);
+        }
+        estimate
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "u64")]
+pub struct Query {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+impl Handler<Query> for SketchAgent {
+    type Result = u64;
+
+    fn handle(&mut self, Query { name, value }: Query, _ctx: &mut Context<Self>) -> Self::Result {
+        match self.sketches.get(&name) {
+            None => 0,
+            Some(sketch) => sketch.query(&value),
+        }
+    }
+}
+
+/// Counts the value once, tracking it in the top-K list of the same name.  The list keeps `k`
+/// members if this creates it; otherwise `k` is ignored.  Resolves to the new estimated count.
+#[derive(Message)]
+#[rtype(result = "u64")]
+pub struct TopKAdd {
+    pub name: String,
+    pub k: u32,
+    pub value: Vec<u8>,
+}
+
+impl Handler<TopKAdd> for SketchAgent {
+    type Result = u64;
+
+    fn handle(&mut self, TopKAdd { name, k, value }: TopKAdd, _ctx: &mut Context<Self>) -> Self::Result {
+        let estimate = self.incr(name.clone(), &value, 1);
+        self.top_k
+            .entry(name)
+            .or_insert_with(|| TopK::new(k))
+            .update(&value, estimate);
+        estimate
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<(Vec<u8>, u64)>")]
+pub struct TopKList {
+    pub name: String,
+}
+
+impl Handler<TopKList> for SketchAgent {
+    type Result = MessageResult<TopKList>;
+
+    fn handle(&mut self, TopKList { name }: TopKList, _ctx: &mut Context<Self>) -> Self::Result {
+        MessageResult(match self.top_k.get(&name) {
+            None => vec![],
+            Some(top_k) => top_k.list(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_exactly_without_collisions() {
+        let mut sketch = CountMinSketch::new(DEFAULT_WIDTH, DEFAULT_DEPTH);
+        assert_eq!(sketch.incr(b"a", 3), 3);
+        assert_eq!(sketch.incr(b"a", 2), 5);
+        assert_eq!(sketch.query(b"a"), 5);
+        assert_eq!(sketch.query(b"b"), 0);
+    }
+
+    #[test]
+    fn never_estimates_below_the_real_count() {
+        // Narrow enough that values collide in every row.
+        let mut sketch = CountMinSketch::new(16, 2);
+        let mut counts = HashMap::new();
+        for i in 0..1000u32 {
+            let value = (i % 100).to_be_bytes();
+            sketch.incr(&value, u64::from(i % 7) + 1);
+            *counts.entry(value).or_insert(0) += u64::from(i % 7) + 1;
+        }
+        for (value, count) in counts {
+            assert!(sketch.query(&value) >= count);
+        }
+    }
+
+    #[test]
+    fn adds_up_every_count_in_a_single_counter() {
+        let mut sketch = CountMinSketch::new(1, 1);
+        sketch.incr(b"a", 2);
+        sketch.incr(b"b", 3);
+        assert_eq!(sketch.query(b"a"), 5);
+        assert_eq!(sketch.query(b"c"), 5);
+    }
+
+    #[test]
+    fn saturates_rather_than_overflowing() {
+        let mut sketch = CountMinSketch::new(DEFAULT_WIDTH, DEFAULT_DEPTH);
+        sketch.incr(b"a", u64::MAX);
+        assert_eq!(sketch.incr(b"a", 1), u64::MAX);
+    }
+
+    #[test]
+    fn lists_most_frequent_first() {
+        let mut top_k = TopK::new(3);
+        top_k.update(b"a", 1);
+        top_k.update(b"b", 3);
+        top_k.update(b"c", 2);
+        assert_eq!(top_k.list(), vec![(b"b".to_vec(), 3), (b"c".to_vec(), 2), (b"a".to_vec(), 1)]);
+    }
+
+    #[test]
+    fn breaks_ties_by_value() {
+        let mut top_k = TopK::new(3);
+        top_k.update(b"b", 1);
+        top_k.update(b"a", 1);
+        assert_eq!(top_k.list(), vec![(b"a".to_vec(), 1), (b"b".to_vec(), 1)]);
+    }
+
+    #[test]
+    fn evicts_the_least_frequent_member_for_one_that_outranks_it() {
+        let mut top_k = TopK::new(2);
+        top_k.update(b"a", 5);
+        top_k.update(b"b", 1);
+        top_k.update(b"c", 2);
+        assert_eq!(top_k.list(), vec![(b"a".to_vec(), 5), (b"c".to_vec(), 2)]);
+    }
+
+    #[test]
+    fn keeps_members_over_values_that_only_match_them() {
+        let mut top_k = TopK::new(2);
+        top_k.update(b"a", 5);
+        top_k.update(b"b", 2);
+        top_k.update(b"c", 2);
+        assert_eq!(top_k.list(), vec![(b"a".to_vec(), 5), (b"b".to_vec(), 2)]);
+    }
+
+    #[test]
+    fn updates_a_members_count_in_place() {
+        let mut top_k = TopK::new(2);
+        top_k.update(b"a", 5);
+        top_k.update(b"b", 2);
+        top_k.update(b"b", 7);
+        assert_eq!(top_k.list(), vec![(b"b".to_vec(), 7), (b"a".to_vec(), 5)]);
+    }
+
+    #[test]
+    fn keeps_the_default_k_when_given_zero() {
+        let mut top_k = TopK::new(0);
+        for i in 0..20u64 {
+            top_k.update(&i.to_be_bytes(), i + 1);
+        }
+        let list = top_k.list();
+        assert_eq!(list.len(), DEFAULT_K as usize);
+        assert_eq!(list[0], (19u64.to_be_bytes().to_vec(), 20));
+        assert_eq!(list[9], (10u64.to_be_bytes().to_vec(), 11));
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use actix::{Actor, Context, Handler, Message, MessageResult};
+use tokio::sync::oneshot;
+
+/// An entry and the ID the server assigned it when it was appended.
+pub type Entry = (u64, Vec<u8>);
+
+// A reader waiting for entries after `after` to be appended.
+struct Waiter {
+    after: u64,
+    count: usize,
+    sender: oneshot::Sender<Vec<Entry>>,
+}
+
+/// An append-only log.  IDs start at 1 and increase by one with every append, so reading after 0
+/// reads from the beginning.
+struct Stream {
+    entries: Vec<Entry>,
+    // The last ID each consumer group has committed to having processed.
+    groups: HashMap<String, u64>,
+    waiters: Vec<Waiter>,
+}
+
+impl Stream {
+    fn new() -> Stream {
+        Stream {
+            entries: vec![],
+            groups: HashMap::new(),
+            waiters: vec![],
+        }
+    }
+
+    fn last_id(&self) -> u64 {
+        self.entries.last().map_or(0, |(id, _)| *id)
+    }
+
+    fn read(&self, after: u64, count: usize) -> Vec<Entry> {
+        // IDs are dense, so the entry with ID n is at index n - 1.
+        let start = (after as usize).min(self.entries.len());
+        let end = start.saturating_add(count).min(self.entries.len());
+        self.entries[start..end].to_vec()
+    }
+}
+
+pub struct StreamAgent {
+    data: HashMap<String, Stream>,
+    // Readers waiting on streams that haven't been appended to yet, kept out here so that reading
+    // never creates a stream.
+    pending: HashMap<String, Vec<Waiter>>,
+}
+
+impl StreamAgent {
+    pub fn new() -> StreamAgent {
+        StreamAgent {
+            data: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl Actor for StreamAgent {
+    type Context = Context<Self>;
+}
+
+/// Appends the value, resolving to its ID.
+#[derive(Message)]
+#[rtype(result = "u64")]
+pub struct Append {
+    pub name: String,
+    pub value: Vec<u8>,
+}
+
+impl Handler<Append> for StreamAgent {
+    type Result = u64;
+
+    fn handle(&mut self, Append { name, value }: Append, _ctx: &mut Context<Self>) -> Self::Result {
+        let pending = self.pending.remove(&name);
+        let stream = self.data.entry(name).or_insert_with(|| {
+            let mut stream = Stream::new();
+            stream.waiters = pending.unwrap_or_default();
+            stream
+        });
+        let id = stream.last_id() + 1;
+        stream.entries.push((id, value));
+
+        for Waiter { after, count, sender } in std::mem::take(&mut stream.waiters) {
+            // Readers that timed out have dropped their end already; nothing to do for them.
+            let _ = sender.send(stream.read(after, count));
+        }
+
+        id
+    }
+}
+
+pub enum StreamError {
+    NotFound { name: String },
+}
+
+pub enum ReadResult {
+    Entries(Vec<Entry>),
+    // Nothing to read yet; the receiver resolves with the first entries appended.
+    Wait(oneshot::Receiver<Vec<Entry>>),
+}
+
+/// Reads up to `count` entries with IDs after `after`, or after the group's committed ID if a
+/// group is given.  With `block` set, waits for new entries rather than returning none.  A stream
+/// that doesn't exist reads as empty, and isn't created.
+#[derive(Message)]
+#[rtype(result = "ReadResult")]
+pub struct Read {
+    pub name: String,
+    pub group: Option<String>,
+    pub after: u64,
+    pub count: usize,
+    pub block: bool,
+}
+
+impl Handler<Read> for StreamAgent {
+    type Result = MessageResult<Read>;
+
+    fn handle(
+        &mut self,
+        Read { name, group, after, count, block }: Read,
+        _ctx: &mut Context<Self>,
+    ) -> Self::Result {
+        let stream = match self.data.get_mut(&name) {
+            Some(stream) => stream,
+            None if block => {
+                // No group has committed anything to a stream that doesn't exist.
+                let after = if group.is_some() { 0 } else { after };
+                let (sender, receiver) = oneshot::channel();
+                // Forgetting the streams whose readers have all timed out, so names read once and
+                // never appended to don't pile up.
+                self.pending.retain(|_, waiters| {
+                    waiters.retain(|waiter| !waiter.sender.is_closed());
+                    !waiters.is_empty()
+                });
+                self.pending.entry(name).or_default().push(Waiter { after, count, sender });
+                return MessageResult(ReadResult::Wait(receiver));
+            }
+            None => return MessageResult(ReadResult::Entries(vec![])),
+        };
+        let after = match group {
+            Some(group) => stream.groups.get(&group).copied().unwrap_or(0),
+            None => after,
+        };
+
+        let entries = stream.read(after, count);
+        if !entries.is_empty() || !block {
+            return MessageResult(ReadResult::Entries(entries));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        stream.waiters.retain(|waiter| !waiter.sender.is_closed());
+        stream.waiters.push(Waiter { after, count, sender });
+        MessageResult(ReadResult::Wait(receiver))
+    }
+}
+
+/// Records that the group has processed every entry up to and including `id`.  Commits never
+/// move a group backwards; resolves to the group's committed ID afterwards.  Unlike appends,
+/// commits don't create the stream, since committing to one that doesn't exist is almost
+/// certainly a typo.
+#[derive(Message)]
+#[rtype(result = "Result<u64, StreamError>")]
+pub struct Commit {
+    pub name: String,
+    pub group: String,
+    pub id: u64,
+}
+
+impl Handler<Commit> for StreamAgent {
+    type Result = Result<u64, StreamError>;
+
+    fn handle(&mut self, Commit { name, group, id }: Commit, _ctx: &mut Context<Self>) -> Self::Result {
+        let stream = match self.data.get_mut(&name) {
+            None => return Err(StreamError::NotFound { name }),
+            Some(stream) => stream,
+        };
+        let id = id.min(stream.last_id());
+        let committed = stream.groups.entry(group).or_insert(0);
+        *committed = (*committed).max(id);
+        Ok(*committed)
+    }
+}